@@ -0,0 +1,118 @@
+use crate::{DNSPacket, DNSRecord, RCode};
+use std::net::IpAddr;
+
+// Typed view over the answer section of a response, for callers that only
+// care about the resolved data and not the wire-level records.
+#[derive(Debug, Clone)]
+pub struct Answer {
+    pub res_code: RCode,
+    pub records: Vec<DNSRecord>,
+}
+
+impl Answer {
+    pub fn ips(&self) -> Vec<IpAddr> {
+        self.records
+            .iter()
+            .filter_map(|record| match record {
+                DNSRecord::A { addr, .. } => Some(IpAddr::V4(*addr)),
+                DNSRecord::AAAA { addr, .. } => Some(IpAddr::V6(*addr)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn cnames(&self) -> Vec<&str> {
+        self.records
+            .iter()
+            .filter_map(|record| match record {
+                DNSRecord::CNAME { host, .. } => Some(host.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn mx(&self) -> Vec<(u16, &str)> {
+        self.records
+            .iter()
+            .filter_map(|record| match record {
+                DNSRecord::MX { priority, host, .. } => Some((*priority, host.as_str())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // One entry per TXT record, its character-strings joined the way SPF and
+    // DKIM expect long values split over several strings to be read
+    pub fn txt(&self) -> Vec<String> {
+        self.records
+            .iter()
+            .filter_map(|record| match record {
+                DNSRecord::TXT { data, .. } => Some(data.concat()),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+impl From<&DNSPacket> for Answer {
+    fn from(packet: &DNSPacket) -> Self {
+        Answer {
+            res_code: packet.header.res_code,
+            records: packet.answers.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{QueryType, CLASS_IN};
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    #[test]
+    fn answer_picks_each_kind_of_record_out_of_a_mixed_packet() {
+        let mut packet = DNSPacket::new();
+        packet.header.res_code = RCode::NOERROR;
+        packet.answers = vec![
+            DNSRecord::CNAME {
+                name: "www.example".to_owned(),
+                q_type: QueryType::CNAME,
+                class: CLASS_IN,
+                ttl: 300,
+                len: 0,
+                host: "web.example".to_owned(),
+            },
+            DNSRecord::new_a("web.example", Ipv4Addr::new(192, 0, 2, 1), 300),
+            DNSRecord::new_aaaa("web.example", Ipv6Addr::LOCALHOST, 300),
+            DNSRecord::MX {
+                name: "example".to_owned(),
+                q_type: QueryType::MX,
+                class: CLASS_IN,
+                ttl: 300,
+                len: 0,
+                priority: 10,
+                host: "mail.example".to_owned(),
+            },
+            DNSRecord::TXT {
+                name: "example".to_owned(),
+                q_type: QueryType::TXT,
+                class: CLASS_IN,
+                ttl: 300,
+                len: 0,
+                data: vec!["v=spf1 ".to_owned(), "-all".to_owned()],
+            },
+        ];
+        let answer = Answer::from(&packet);
+        assert_eq!(answer.res_code, RCode::NOERROR);
+        assert_eq!(
+            answer.ips(),
+            vec![
+                IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+                IpAddr::V6(Ipv6Addr::LOCALHOST)
+            ]
+        );
+        assert_eq!(answer.cnames(), vec!["web.example"]);
+        assert_eq!(answer.mx(), vec![(10, "mail.example")]);
+        assert_eq!(answer.txt(), vec!["v=spf1 -all".to_owned()]);
+    }
+}
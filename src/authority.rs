@@ -0,0 +1,180 @@
+use crate::{is_subdomain_of, normalize_name, DNSPacket, DNSRecord, QueryType};
+use eyre::{eyre, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+const DEFAULT_TTL: u32 = 300;
+
+#[derive(Debug, Deserialize)]
+struct JsonRecord {
+    name: String,
+    #[serde(rename = "type")]
+    q_type: String,
+    ttl: Option<u32>,
+    value: String,
+}
+
+// Records this server answers for itself, keyed by normalized owner name
+#[derive(Debug, Clone, Default)]
+pub struct AuthorityStore {
+    records: HashMap<String, Vec<DNSRecord>>,
+}
+
+impl AuthorityStore {
+    pub fn new() -> AuthorityStore {
+        AuthorityStore {
+            records: HashMap::new(),
+        }
+    }
+
+    pub fn add_record(&mut self, record: DNSRecord) {
+        self.records
+            .entry(normalize_name(record.get_name()))
+            .or_default()
+            .push(record);
+    }
+
+    // Returns None for names we know nothing about so they can be recursed, and
+    // an authoritative (possibly empty) answer for names we own.
+    pub fn lookup(&self, qname: &str, q_type: QueryType) -> Option<DNSPacket> {
+        let qname = normalize_name(qname);
+        let mut packet = DNSPacket::new();
+        packet.header.auth_answer = true;
+        let records = match self.records.get(&qname) {
+            Some(records) => records,
+            // An empty non-terminal exists because its descendants do, it just has no data
+            None if self.is_empty_non_terminal(&qname) => return Some(packet),
+            None => return None,
+        };
+        packet.answers = records
+            .iter()
+            .filter(|record| record.get_type() == q_type || record.get_type() == QueryType::CNAME)
+            .cloned()
+            .collect();
+        Some(packet)
+    }
+
+    fn is_empty_non_terminal(&self, qname: &str) -> bool {
+        self.records
+            .keys()
+            .any(|name| name != qname && is_subdomain_of(name, qname))
+    }
+}
+
+fn parse_json_record(record: JsonRecord) -> Result<DNSRecord> {
+    let name = record.name;
+    let ttl = record.ttl.unwrap_or(DEFAULT_TTL);
+    // RDLENGTH is recomputed when the record is written, so `len` is left at 0
+    match record.q_type.to_uppercase().as_str() {
+        "A" => Ok(DNSRecord::new_a(&name, record.value.parse()?, ttl)),
+        "AAAA" => Ok(DNSRecord::new_aaaa(&name, record.value.parse()?, ttl)),
+        "NS" => Ok(DNSRecord::NS {
+            name,
+            q_type: QueryType::NS,
+            class: 1,
+            ttl,
+            len: 0,
+            host: record.value,
+        }),
+        "CNAME" => Ok(DNSRecord::CNAME {
+            name,
+            q_type: QueryType::CNAME,
+            class: 1,
+            ttl,
+            len: 0,
+            host: record.value,
+        }),
+        "MX" => {
+            let mut parts = record.value.split_whitespace();
+            let priority = parts
+                .next()
+                .ok_or_else(|| eyre!("MX record for {} is missing a priority", name))?
+                .parse()?;
+            let host = parts
+                .next()
+                .ok_or_else(|| eyre!("MX record for {} is missing a host", name))?
+                .to_owned();
+            Ok(DNSRecord::MX {
+                name,
+                q_type: QueryType::MX,
+                class: 1,
+                ttl,
+                len: 0,
+                priority,
+                host,
+            })
+        }
+        other => Err(eyre!("Unsupported record type {} for {}", other, name)),
+    }
+}
+
+// Loads a list of `{"name", "type", "ttl", "value"}` objects, e.g.
+// `[{"name": "www.example.test", "type": "A", "ttl": 60, "value": "10.0.0.1"}]`
+pub fn load_records_json<P: AsRef<Path>>(path: P) -> Result<AuthorityStore> {
+    let contents = fs::read_to_string(path)?;
+    let records: Vec<JsonRecord> = serde_json::from_str(&contents)?;
+    let mut store = AuthorityStore::new();
+    for record in records {
+        store.add_record(parse_json_record(record)?);
+    }
+    Ok(store)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RCode;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn answers_from_a_loaded_json_record_set() {
+        let path =
+            std::env::temp_dir().join(format!("diglett-records-{}.json", std::process::id()));
+        fs::write(
+            &path,
+            r#"[
+                {"name": "www.example.test", "type": "A", "ttl": 60, "value": "10.0.0.1"},
+                {"name": "example.test", "type": "mx", "value": "10 mail.example.test"}
+            ]"#,
+        )
+        .unwrap();
+        let store = load_records_json(&path);
+        fs::remove_file(&path).unwrap();
+        let store = store.unwrap();
+
+        let packet = store.lookup("WWW.example.test.", QueryType::A).unwrap();
+        assert!(packet.header.auth_answer);
+        assert_eq!(packet.header.res_code, RCode::NOERROR);
+        assert_eq!(
+            packet.answers,
+            vec![DNSRecord::new_a(
+                "www.example.test",
+                Ipv4Addr::new(10, 0, 0, 1),
+                60
+            )]
+        );
+        assert_eq!(
+            store.lookup("example.test", QueryType::MX).unwrap().answers[0].get_ttl(),
+            DEFAULT_TTL
+        );
+        assert!(store.lookup("other.test", QueryType::A).is_none());
+    }
+
+    #[test]
+    fn empty_non_terminal_is_nodata() {
+        let mut store = AuthorityStore::new();
+        store.add_record(DNSRecord::new_a(
+            "a.b.example.test",
+            Ipv4Addr::new(10, 0, 0, 2),
+            60,
+        ));
+
+        let packet = store.lookup("b.example.test", QueryType::A).unwrap();
+        assert!(packet.header.auth_answer);
+        assert_eq!(packet.header.res_code, RCode::NOERROR);
+        assert!(packet.answers.is_empty());
+        assert!(store.lookup("c.example.test", QueryType::A).is_none());
+    }
+}
@@ -1,7 +1,16 @@
 use eyre::{eyre, Result};
 use std::collections::HashMap;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::UdpSocket;
+
+// Far more than any real TXT record carries, but keeps a crafted RDATA of
+// empty strings from turning into tens of thousands of allocations
+pub const MAX_CHARACTER_STRINGS: usize = 256;
+
+// A name has at most 127 labels, so no legitimate name needs more pointers
+pub const MAX_QNAME_JUMPS: usize = 127;
 
 pub trait PacketBufferTrait {
     fn read(&mut self) -> Result<u8>;
@@ -37,9 +46,53 @@ pub trait PacketBufferTrait {
         Ok(())
     }
 
+    fn read_bytes(&mut self, len: usize) -> Result<Vec<u8>> {
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+        let res = self.get_range(self.pos(), len)?.to_vec();
+        self.step(len)?;
+        Ok(res)
+    }
+
+    // Reads the length-prefixed character-strings (RFC 1035 section 3.3) filling
+    // exactly `len` bytes of RDATA. A string running past the end is an error,
+    // never a read into whatever follows the record.
+    fn read_character_strings(&mut self, len: usize) -> Result<Vec<String>> {
+        let end = self.pos() + len;
+        let mut strings = Vec::new();
+        while self.pos() < end {
+            if strings.len() == MAX_CHARACTER_STRINGS {
+                return Err(eyre!(
+                    "RDATA holds more than {} character-strings",
+                    MAX_CHARACTER_STRINGS
+                ));
+            }
+            let str_len = self.read()? as usize;
+            if self.pos() + str_len > end {
+                return Err(eyre!(
+                    "Character-string of length {} overruns its {} byte RDATA",
+                    str_len,
+                    len
+                ));
+            }
+            let bytes = self.read_bytes(str_len)?;
+            strings.push(String::from_utf8_lossy(&bytes).into_owned());
+        }
+        Ok(strings)
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<()> {
+        for byte in bytes {
+            self.write(*byte)?;
+        }
+        Ok(())
+    }
+
     fn read_qname(&mut self, output: &mut String) -> Result<()> {
         let mut pos = self.pos();
         let mut jump = false;
+        let mut jumps_performed = 0;
         let mut delim = "";
 
         loop {
@@ -50,6 +103,15 @@ pub trait PacketBufferTrait {
                     self.seek(pos + 2)?;
                 }
 
+                // A pointer loop would otherwise never reach the null label
+                jumps_performed += 1;
+                if jumps_performed > MAX_QNAME_JUMPS {
+                    return Err(eyre!(
+                        "Limit of {} compression pointers exceeded in name",
+                        MAX_QNAME_JUMPS
+                    ));
+                }
+
                 let byte2 = self.get(pos + 1)? as u16;
                 let offset = ((len as u16) ^ 0xC0) << 8 | byte2;
                 pos = offset as usize;
@@ -77,7 +139,24 @@ pub trait PacketBufferTrait {
     }
 
     fn write_qname(&mut self, qname: &str) -> Result<()> {
+        let qname = qname.trim_end_matches('.');
+        if qname.is_empty() {
+            // The root name is just the terminating null label
+            return self.write(0);
+        }
         let qname_split = qname.split('.').collect::<Vec<&str>>();
+        // Every label costs its length byte plus data, and the name ends with a null label
+        let encoded_len = qname_split
+            .iter()
+            .map(|label| label.len() + 1)
+            .sum::<usize>()
+            + 1;
+        if encoded_len > 255 {
+            return Err(eyre!(
+                "Name exceeds 255 octets, encoded length: {}",
+                encoded_len
+            ));
+        }
         for (i, label) in qname_split.iter().enumerate() {
             let search_label = qname_split[i..].join(".");
             if let Some(label_pos) = self.get_label(&search_label) {
@@ -85,7 +164,10 @@ pub trait PacketBufferTrait {
                 self.write_u16(jump_pos)?;
                 return Ok(());
             }
-            self.set_label(&search_label, self.pos());
+            // Pointers only have 14 bits, names further in can't be referenced
+            if self.pos() <= 0x3FFF {
+                self.set_label(&search_label, self.pos());
+            }
 
             let len = label.len();
             if len > 63 {
@@ -107,6 +189,8 @@ pub struct ArrayBuffer {
     pub buf: [u8; 512],
     pub pos: usize,
     pub label_map: HashMap<String, usize>,
+    // Bytes of `buf` holding real data, received or written
+    pub filled: usize,
 }
 
 impl PacketBufferTrait for ArrayBuffer {
@@ -114,8 +198,10 @@ impl PacketBufferTrait for ArrayBuffer {
         self.pos
     }
 
+    // Reads are bounded by what was actually received, so a short packet
+    // fails to parse instead of reading the zeroed rest of the array
     fn step(&mut self, steps: usize) -> Result<()> {
-        if self.pos + steps > 512 {
+        if self.pos + steps > self.filled {
             return Err(eyre!("Buffer position exceeded, pos: {}", self.pos));
         }
         self.pos += steps;
@@ -123,7 +209,7 @@ impl PacketBufferTrait for ArrayBuffer {
     }
 
     fn seek(&mut self, pos: usize) -> Result<()> {
-        if pos > 512 {
+        if pos > self.filled {
             return Err(eyre!("Buffer position exceeded, pos: {}", pos));
         }
         self.pos = pos;
@@ -131,7 +217,7 @@ impl PacketBufferTrait for ArrayBuffer {
     }
 
     fn read(&mut self) -> Result<u8> {
-        if self.pos >= 512 {
+        if self.pos >= self.filled {
             return Err(eyre!("Buffer position exceeded, pos: {}", self.pos));
         }
         let result = self.buf[self.pos];
@@ -140,7 +226,7 @@ impl PacketBufferTrait for ArrayBuffer {
     }
 
     fn get(&self, pos: usize) -> Result<u8> {
-        if pos >= 512 {
+        if pos >= self.filled {
             return Err(eyre!("GET: Buffer position exceeded, pos: {}", self.pos));
         }
         let res = self.buf[pos];
@@ -148,9 +234,16 @@ impl PacketBufferTrait for ArrayBuffer {
     }
 
     fn get_range(&self, pos: usize, len: usize) -> Result<&[u8]> {
-        if pos >= 512 {
+        if pos >= self.filled {
             return Err(eyre!("Buffer position exceeded, pos: {}", self.pos));
         }
+        if pos + len > self.filled {
+            return Err(eyre!(
+                "Range of {} bytes at {} runs past the end of the buffer",
+                len,
+                pos
+            ));
+        }
         let res = &self.buf[pos..pos + len];
         Ok(res)
     }
@@ -166,6 +259,7 @@ impl PacketBufferTrait for ArrayBuffer {
         }
         self.buf[self.pos] = val;
         self.pos += 1;
+        self.filled = self.filled.max(self.pos);
         Ok(())
     }
 
@@ -178,13 +272,28 @@ impl PacketBufferTrait for ArrayBuffer {
     }
 }
 
+impl Default for ArrayBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ArrayBuffer {
     pub fn new() -> ArrayBuffer {
-        return ArrayBuffer {
+        ArrayBuffer {
             buf: [0; 512],
             pos: 0,
             label_map: HashMap::new(),
-        };
+            filled: 0,
+        }
+    }
+
+    // Receives one datagram into the buffer, remembering how much of it arrived
+    pub async fn recv_from(&mut self, socket: &mut UdpSocket) -> Result<(usize, SocketAddr)> {
+        let (len, src) = socket.recv_from(&mut self.buf).await?;
+        self.pos = 0;
+        self.filled = len;
+        Ok((len, src))
     }
 }
 
@@ -236,6 +345,13 @@ impl PacketBufferTrait for VecBuffer {
         if pos >= self.buf.len() {
             return Err(eyre!("Buffer position exceeded, pos: {}", self.pos));
         }
+        if pos + len > self.buf.len() {
+            return Err(eyre!(
+                "Range of {} bytes at {} runs past the end of the buffer",
+                len,
+                pos
+            ));
+        }
         let res = &self.buf[pos..pos + len];
         Ok(res)
     }
@@ -260,6 +376,59 @@ impl PacketBufferTrait for VecBuffer {
     }
 }
 
+// Writes names in full, never as compression pointers, as the canonical form
+// of records that digests are computed over requires (RFC 4034 section 6.2)
+#[derive(Default)]
+pub struct UncompressedBuffer {
+    pub inner: VecBuffer,
+}
+
+impl PacketBufferTrait for UncompressedBuffer {
+    fn pos(&self) -> usize {
+        self.inner.pos()
+    }
+
+    fn step(&mut self, steps: usize) -> Result<()> {
+        self.inner.step(steps)
+    }
+
+    fn seek(&mut self, pos: usize) -> Result<()> {
+        self.inner.seek(pos)
+    }
+
+    fn read(&mut self) -> Result<u8> {
+        self.inner.read()
+    }
+
+    fn get(&self, pos: usize) -> Result<u8> {
+        self.inner.get(pos)
+    }
+
+    fn get_range(&self, pos: usize, len: usize) -> Result<&[u8]> {
+        self.inner.get_range(pos, len)
+    }
+
+    fn set(&mut self, pos: usize, val: u8) -> Result<()> {
+        self.inner.set(pos, val)
+    }
+
+    fn write(&mut self, val: u8) -> Result<()> {
+        self.inner.write(val)
+    }
+
+    fn get_label(&self, _key: &str) -> Option<usize> {
+        None
+    }
+
+    fn set_label(&mut self, _key: &str, _value: usize) {}
+}
+
+impl Default for VecBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl VecBuffer {
     pub fn new() -> VecBuffer {
         VecBuffer {
@@ -269,11 +438,41 @@ impl VecBuffer {
         }
     }
 
-    pub async fn from_socket(socket: &mut TcpStream) -> Result<VecBuffer> {
-        let size = socket.read_u16().await?;
-        let mut new_socket = socket.take(size as u64);
-        let mut res_vec = Vec::with_capacity(size as usize);
-        new_socket.read_to_end(&mut res_vec).await?;
+    // Receives one datagram of at most `capacity` bytes
+    pub async fn recv_from(
+        socket: &mut UdpSocket,
+        capacity: usize,
+    ) -> Result<(VecBuffer, usize, SocketAddr)> {
+        let mut buf = vec![0; capacity];
+        let (len, src) = socket.recv_from(&mut buf).await?;
+        buf.truncate(len);
+        Ok((
+            VecBuffer {
+                buf,
+                pos: 0,
+                label_map: HashMap::new(),
+            },
+            len,
+            src,
+        ))
+    }
+
+    // Reads one length-prefixed message off a stream, TCP or TLS
+    pub async fn from_socket<S: AsyncRead + Unpin>(socket: &mut S) -> Result<VecBuffer> {
+        let size = match socket.read_u16().await {
+            Ok(size) => size,
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
+                return Err(eyre!("Connection closed before a message length was sent"));
+            }
+            Err(err) => return Err(err.into()),
+        };
+        if size == 0 {
+            return Err(eyre!("Peer announced an empty message"));
+        }
+        // read_exact keeps reading across TCP segments and fails with
+        // UnexpectedEof if the peer closes before the whole message arrives
+        let mut res_vec = vec![0; size as usize];
+        socket.read_exact(&mut res_vec).await?;
         Ok(VecBuffer {
             buf: res_vec,
             pos: 0,
@@ -281,10 +480,116 @@ impl VecBuffer {
         })
     }
 
-    pub async fn to_socket(&mut self, socket: &mut TcpStream) -> Result<()> {
+    pub async fn to_socket<S: AsyncWrite + Unpin>(&mut self, socket: &mut S) -> Result<()> {
         let size = self.buf.len();
         socket.write_u16(size as u16).await?;
-        socket.write(&self.buf).await?;
+        socket.write_all(&self.buf).await?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    // Hands out its data one byte per read, like a message split across many segments
+    struct Trickle(Vec<u8>);
+
+    impl AsyncRead for Trickle {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            if self.0.is_empty() || buf.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+            buf[0] = self.0.remove(0);
+            Poll::Ready(Ok(1))
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_a_message_split_across_reads() {
+        let mut socket = Trickle(vec![0, 3, 1, 2, 3, 9]);
+        let buffer = VecBuffer::from_socket(&mut socket).await.unwrap();
+        assert_eq!(buffer.buf, vec![1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn short_message_fails_with_unexpected_eof() {
+        let mut socket: &[u8] = &[0, 5, 1, 2];
+        let err = match VecBuffer::from_socket(&mut socket).await {
+            Ok(_) => panic!("parsed a message missing 3 of its bytes"),
+            Err(err) => err,
+        };
+        let err = err.downcast_ref::<io::Error>().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn eof_before_the_length_is_an_error() {
+        let mut socket: &[u8] = &[0];
+        assert!(VecBuffer::from_socket(&mut socket).await.is_err());
+        let mut socket: &[u8] = &[0, 0];
+        assert!(VecBuffer::from_socket(&mut socket).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn received_length_is_tracked() {
+        let mut receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        sender
+            .send_to(&[1, 2, 3], receiver.local_addr().unwrap())
+            .await
+            .unwrap();
+        let mut buffer = ArrayBuffer::new();
+        buffer.buf[3] = 0xFF;
+        let (len, src) = buffer.recv_from(&mut receiver).await.unwrap();
+        assert_eq!(src, sender.local_addr().unwrap());
+        assert_eq!((len, buffer.filled, buffer.pos()), (3, 3, 0));
+        assert_eq!(buffer.get_range(0, 3).unwrap(), [1, 2, 3]);
+        // Whatever was in the buffer past the datagram is out of reach
+        assert!(buffer.get(3).is_err());
+        assert!(buffer.get_range(2, 2).is_err());
+        buffer.seek(3).unwrap();
+        assert!(buffer.read().is_err());
+    }
+
+    #[test]
+    fn repeated_suffixes_are_written_as_pointers() {
+        let names = ["ns1.example.com", "ns2.example.com", "example.com"];
+        let mut buffer = VecBuffer::new();
+        for name in names.iter() {
+            buffer.write_qname(name).unwrap();
+        }
+        // 17 bytes in full, then a label and a pointer, then just a pointer
+        assert_eq!(buffer.buf.len(), 17 + 6 + 2);
+        assert_eq!(buffer.buf[21..23], [0xC0, 4]);
+        assert_eq!(buffer.buf[23..], [0xC0, 4]);
+
+        buffer.seek(0).unwrap();
+        for name in names.iter() {
+            let mut read = String::new();
+            buffer.read_qname(&mut read).unwrap();
+            assert_eq!(&read, name);
+        }
+        assert_eq!(buffer.pos(), buffer.buf.len());
+    }
+
+    #[test]
+    fn names_over_255_octets_are_not_written() {
+        let label = "a".repeat(63);
+        // Four 63 byte labels take 4 * 64 + 1 = 257 octets
+        let too_long = [label.as_str(); 4].join(".");
+        assert!(VecBuffer::new().write_qname(&too_long).is_err());
+        // Exactly 255 octets still fits
+        let longest = format!("{}.{}.{}.{}", label, label, label, "a".repeat(61));
+        let mut buffer = VecBuffer::new();
+        buffer.write_qname(&longest).unwrap();
+        assert_eq!(buffer.buf.len(), 255);
+    }
+}
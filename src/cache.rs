@@ -1,20 +1,83 @@
-use crate::{QueryType, DNSRecord, DNSPacket};
+use crate::{is_subdomain_of, normalize_name, QueryClass, QueryType, DNSRecord, DNSPacket, RCode};
+use crate::upstream::UpstreamStats;
 use std::collections::HashMap;
-use std::time::{Duration, SystemTime};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 use std::sync::{Arc, Mutex};
 
+// Records are shared between the entry for a whole response and the entries
+// for its individual RRsets instead of being copied into each of them
 #[derive(Debug,Clone)]
 pub struct CacheEntry {
-    pub records: Vec<DNSRecord>,
-    pub timestamp: SystemTime,
+    pub answers: Vec<Arc<DNSRecord>>,
+    pub authority: Vec<Arc<DNSRecord>>,
+    pub additional: Vec<Arc<DNSRecord>>,
+    pub timestamp: Instant,
+    pub res_code: RCode,
+    // Set for NXDOMAIN/NODATA entries, how long the negative answer may be reused
+    pub negative_ttl: Option<u32>,
 }
 
-#[derive(Debug,Clone)]
+impl CacheEntry {
+    fn fresh_records(&self, records: &[Arc<DNSRecord>], timestamp_now: Instant) -> Vec<Arc<DNSRecord>> {
+        records.iter().filter(|record| {
+            Duration::new(record.get_ttl() as u64, 0) > timestamp_now.saturating_duration_since(self.timestamp)
+        }).cloned().collect::<Vec<Arc<DNSRecord>>>()
+    }
+
+    fn is_stale(&self, timestamp_now: Instant) -> bool {
+        match self.negative_ttl {
+            Some(ttl) => Duration::new(ttl as u64, 0) <= timestamp_now.saturating_duration_since(self.timestamp),
+            None => false,
+        }
+    }
+}
+
+fn aged(records: Vec<DNSRecord>, elapsed: u64) -> Vec<DNSRecord> {
+    records.into_iter().filter_map(|mut record| {
+        let ttl = (record.get_ttl() as u64).saturating_sub(elapsed);
+        if ttl == 0 {
+            return None;
+        }
+        record.set_ttl(ttl as u32);
+        Some(record)
+    }).collect()
+}
+
+// RFC 2308 section 5: a negative answer lives for the lesser of the SOA's own
+// TTL and its MINIMUM field. Without a SOA it must not be cached at all.
+fn negative_ttl(packet: &DNSPacket) -> Option<u32> {
+    packet.authority.iter().find_map(|record| match record {
+        DNSRecord::SOA { ttl, minimum, .. } => Some((*ttl).min(*minimum)),
+        _ => None,
+    })
+}
+
+fn share(records: Vec<DNSRecord>) -> Vec<Arc<DNSRecord>> {
+    records.into_iter().map(Arc::new).collect()
+}
+
+fn unshare(records: Vec<Arc<DNSRecord>>) -> Vec<DNSRecord> {
+    records.into_iter().map(|record| Arc::try_unwrap(record).unwrap_or_else(|shared| (*shared).clone())).collect()
+}
+
+#[derive(Debug,Clone,Default)]
 pub struct DNSCache {
-    pub map: Arc<Mutex<HashMap<(String, QueryType), CacheEntry>>>
+    pub map: Arc<Mutex<HashMap<(String, QueryType), CacheEntry>>>,
+    // Nameserver round trip times, used to pick the fastest server of a delegation
+    pub upstreams: UpstreamStats,
 }
 
 impl DNSCache {
+    pub fn new() -> DNSCache {
+        DNSCache::default()
+    }
+
+    // Every spelling of a name (case, trailing dot) maps to the same entry
+    pub fn key(qname: &str, q_type: QueryType) -> (String, QueryType) {
+        (normalize_name(qname), q_type)
+    }
+
     // pub fn get_nearest_a_record(&self, qname: &str, q_type: QueryType) -> Option<Ipv4Addr> {
     //     let qname_split = qname.split(".").collect::<Vec<&str>>();
     //     for (i, _) in qname_split.iter().enumerate() {
@@ -37,33 +100,392 @@ impl DNSCache {
     //     None
     // }
 
-    pub fn get_records(&self, qname: &str, q_type: QueryType) -> Option<DNSPacket> {
-        if let Some(entry) = self.map.lock().unwrap().get(&(qname.to_owned(), q_type)) {
-            let timestamp_now = SystemTime::now();
-            let records = entry.records.iter().filter_map(|record| {
-                if Duration::new(record.get_ttl() as u64, 0) > timestamp_now.duration_since(entry.timestamp).unwrap() {
-                    Some(record.clone())
-                } else {
-                    None
-                }
-            }).collect::<Vec<DNSRecord>>();
-            if records.len() == 0 {
+    // Fresh records of the entry, only bumping reference counts while the lock is held
+    pub fn get_entry(&self, qname: &str, q_type: QueryType) -> Option<CacheEntry> {
+        if let Some(entry) = self.map.lock().unwrap().get(&DNSCache::key(qname, q_type)) {
+            let timestamp_now = Instant::now();
+            if entry.is_stale(timestamp_now) {
                 return None;
             }
-            return Some(records.into());
+            let fresh = CacheEntry {
+                answers: entry.fresh_records(&entry.answers, timestamp_now),
+                authority: entry.fresh_records(&entry.authority, timestamp_now),
+                additional: entry.fresh_records(&entry.additional, timestamp_now),
+                timestamp: entry.timestamp,
+                res_code: entry.res_code,
+                negative_ttl: entry.negative_ttl,
+            };
+            if fresh.answers.is_empty() && fresh.authority.is_empty() {
+                return None;
+            }
+            return Some(fresh);
         }
         None
     }
 
-    pub fn set_records(&mut self, qname: &str, q_type: QueryType, mut packet: DNSPacket) {
-        let timestamp = SystemTime::now();
-        let mut records = packet.answers.clone();
-        records.append(&mut packet.authority);
-        records.append(&mut packet.addtional);
+    // Served records count down: their TTLs have the time spent in the cache taken off
+    pub fn get_records(&self, qname: &str, q_type: QueryType) -> Option<DNSPacket> {
+        let entry = self.get_entry(qname, q_type)?;
+        let elapsed = entry.timestamp.elapsed().as_secs();
+        let mut packet = DNSPacket::new();
+        packet.header.res_code = entry.res_code;
+        packet.answers = aged(unshare(entry.answers), elapsed);
+        packet.authority = aged(unshare(entry.authority), elapsed);
+        packet.addtional = aged(unshare(entry.additional), elapsed);
+        if packet.answers.is_empty() && packet.authority.is_empty() {
+            return None;
+        }
+        Some(packet)
+    }
+
+    // Every cached record owned by `qname`, whatever its type, for ANY questions
+    pub fn get_any(&self, qname: &str) -> Option<DNSPacket> {
+        let qname = normalize_name(qname);
+        let keys = self.map.lock().unwrap().keys()
+            .filter(|(name, q_type)| *name == qname && *q_type != QueryType::ANY)
+            .cloned()
+            .collect::<Vec<(String, QueryType)>>();
+        let mut packet = DNSPacket::new();
+        for (name, q_type) in keys {
+            if let Some(cached) = self.get_records(&name, q_type) {
+                if cached.header.res_code != RCode::NOERROR {
+                    continue;
+                }
+                for record in cached.answers {
+                    if normalize_name(record.get_name()) == qname && !packet.answers.contains(&record) {
+                        packet.answers.push(record);
+                    }
+                }
+            }
+        }
+        if packet.answers.is_empty() {
+            return None;
+        }
+        Some(packet)
+    }
+
+    // Caches every RRset of a response the server is in bailiwick for under its
+    // own owner name and type, e.g. the NS records and glue of a referral.
+    pub fn set_rrsets(&mut self, packet: &DNSPacket, bailiwick: &str) {
+        let packet = packet.compacted();
+        let records = share(packet.answers).into_iter()
+            .chain(share(packet.authority))
+            .chain(share(packet.addtional))
+            .collect::<Vec<Arc<DNSRecord>>>();
+        self.insert_rrsets(&records, bailiwick);
+    }
+
+    fn insert_rrsets(&mut self, records: &[Arc<DNSRecord>], bailiwick: &str) {
+        let timestamp = Instant::now();
+        let mut rrsets: HashMap<(String, QueryType), Vec<Arc<DNSRecord>>> = HashMap::new();
+        for record in records {
+            if !is_subdomain_of(record.get_name(), bailiwick) {
+                continue;
+            }
+            rrsets
+                .entry(DNSCache::key(record.get_name(), record.get_type()))
+                .or_default()
+                .push(record.clone());
+        }
+
+        let mut map = self.map.lock().unwrap();
+        for (key, records) in rrsets {
+            // TTL 0 means "do not cache", which also makes any older copy stale
+            if records.iter().any(|record| record.get_ttl() == 0) {
+                map.remove(&key);
+                continue;
+            }
+            map.insert(key, CacheEntry {
+                answers: records,
+                authority: Vec::new(),
+                additional: Vec::new(),
+                timestamp,
+                res_code: RCode::NOERROR,
+                negative_ttl: None,
+            });
+        }
+    }
+
+    // Caches the whole response as the answer to (qname, q_type), along with its RRsets
+    pub fn set_records(&mut self, qname: &str, q_type: QueryType, packet: &DNSPacket, bailiwick: &str) {
+        let res_code = packet.header.res_code;
+        let negative = res_code == RCode::NXDOMAIN || packet.is_nodata();
+        let negative_ttl = negative_ttl(packet);
+        // The OPT record belongs to the transaction that fetched the response
+        let packet = packet.compacted();
+        let answers = share(packet.answers);
+        let authority = share(packet.authority);
+        let additional = share(packet.addtional);
+        let all_records = answers.iter().chain(authority.iter()).chain(additional.iter())
+            .cloned()
+            .collect::<Vec<Arc<DNSRecord>>>();
+        self.insert_rrsets(&all_records, bailiwick);
+        if answers.iter().any(|record| record.get_ttl() == 0) {
+            self.map.lock().unwrap().remove(&DNSCache::key(qname, q_type));
+            return;
+        }
+        let negative_ttl = if negative {
+            match negative_ttl {
+                Some(ttl) => Some(ttl),
+                None => return,
+            }
+        } else {
+            None
+        };
+        let timestamp = Instant::now();
         let entry = CacheEntry {
-            records,
-            timestamp
+            answers,
+            authority,
+            additional,
+            timestamp,
+            res_code,
+            negative_ttl,
         };
-        self.map.lock().unwrap().insert((qname.to_owned(), q_type), entry);
+        self.map.lock().unwrap().insert(DNSCache::key(qname, q_type), entry);
     }
-}
\ No newline at end of file
+}
+
+// Client address, query id and question, what a retransmission repeats
+pub type DedupKey = (SocketAddr, u16, Vec<(String, QueryType, QueryClass)>);
+
+#[derive(Debug,Clone)]
+enum DedupEntry {
+    // Still being resolved, the response to the first copy will answer the client
+    Pending,
+    // When the response went out, and its bytes
+    Sent(Instant, Vec<u8>),
+}
+
+// What to do with a query, see `ResponseDedup::claim`
+#[derive(Debug)]
+pub enum Dedup {
+    // First copy of the query, resolve it and `complete` the claim
+    Claimed(DedupClaim),
+    // A retransmission of a query still being resolved, drop it
+    InFlight,
+    // A retransmission of a query just answered, send these bytes again
+    Sent(Vec<u8>),
+}
+
+// Marks a query as being resolved. Dropped without `complete`, e.g. when the
+// request fails, it lets the next retransmission be resolved again.
+#[derive(Debug)]
+pub struct DedupClaim {
+    dedup: ResponseDedup,
+    key: Option<DedupKey>,
+}
+
+impl DedupClaim {
+    pub fn complete(mut self, response: Vec<u8>) {
+        if let Some(key) = self.key.take() {
+            self.dedup.insert(key, response);
+        }
+    }
+}
+
+impl Drop for DedupClaim {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.dedup.map.lock().unwrap().remove(&key);
+        }
+    }
+}
+
+// Queries in flight and responses just sent to UDP clients, so retransmitted
+// queries join the pending lookup or, within `window` of the response, get the
+// same bytes back instead of being resolved again
+#[derive(Debug,Clone)]
+pub struct ResponseDedup {
+    window: Duration,
+    map: Arc<Mutex<HashMap<DedupKey, DedupEntry>>>,
+}
+
+impl ResponseDedup {
+    pub fn new(window: Duration) -> ResponseDedup {
+        ResponseDedup {
+            window,
+            map: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn key(src: SocketAddr, request: &DNSPacket) -> DedupKey {
+        let questions = request.questions.iter().map(|question| {
+            (normalize_name(&question.name), question.q_type, question.class)
+        }).collect();
+        (src, request.header.id, questions)
+    }
+
+    // Checks for an earlier copy of the query and claims it when there is none,
+    // in one step so two copies arriving together aren't both resolved
+    pub fn claim(&self, key: DedupKey) -> Dedup {
+        let mut map = self.map.lock().unwrap();
+        match map.get(&key) {
+            Some(DedupEntry::Pending) => return Dedup::InFlight,
+            Some(DedupEntry::Sent(sent, response)) if sent.elapsed() < self.window => {
+                return Dedup::Sent(response.clone());
+            }
+            _ => {}
+        }
+        map.insert(key.clone(), DedupEntry::Pending);
+        Dedup::Claimed(DedupClaim { dedup: self.clone(), key: Some(key) })
+    }
+
+    fn insert(&self, key: DedupKey, response: Vec<u8>) {
+        let mut map = self.map.lock().unwrap();
+        // Expired entries go on every insert, the map only ever holds one window
+        // of traffic besides the queries in flight
+        let window = self.window;
+        map.retain(|_, entry| match entry {
+            DedupEntry::Pending => true,
+            DedupEntry::Sent(sent, _) => sent.elapsed() < window,
+        });
+        map.insert(key, DedupEntry::Sent(Instant::now(), response));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn ns_record(name: &str, host: &str) -> DNSRecord {
+        DNSRecord::NS { name: name.to_owned(), q_type: QueryType::NS, class: 1, ttl: 300, len: 0, host: host.to_owned() }
+    }
+
+    fn referral_answer() -> DNSPacket {
+        let mut packet = DNSPacket::new();
+        packet.answers.push(DNSRecord::new_a("www.example", Ipv4Addr::new(192, 0, 2, 1), 300));
+        packet.authority.push(ns_record("example", "ns1.example"));
+        packet.addtional.push(DNSRecord::new_a("ns1.example", Ipv4Addr::new(192, 0, 2, 53), 300));
+        packet
+    }
+
+    fn backdate(cache: &DNSCache, qname: &str, q_type: QueryType, timestamp: Instant) {
+        cache.map.lock().unwrap().get_mut(&DNSCache::key(qname, q_type)).unwrap().timestamp = timestamp;
+    }
+
+    #[test]
+    fn keeps_authority_and_additional_sections() {
+        let mut cache = DNSCache::new();
+        cache.set_records("www.example", QueryType::A, &referral_answer(), "example");
+        let cached = cache.get_records("WWW.example.", QueryType::A).unwrap();
+        assert_eq!(cached.answers.len(), 1);
+        assert_eq!(cached.authority, vec![ns_record("example", "ns1.example")]);
+        assert_eq!(cached.addtional.len(), 1);
+    }
+
+    #[test]
+    fn counts_ttls_down_and_expires_records() {
+        let mut cache = DNSCache::new();
+        cache.set_records("www.example", QueryType::A, &referral_answer(), "example");
+        backdate(&cache, "www.example", QueryType::A, Instant::now() - Duration::from_secs(100));
+        let cached = cache.get_records("www.example", QueryType::A).unwrap();
+        assert_eq!(cached.answers[0].get_ttl(), 200);
+        backdate(&cache, "www.example", QueryType::A, Instant::now() - Duration::from_secs(300));
+        assert!(cache.get_records("www.example", QueryType::A).is_none());
+    }
+
+    #[test]
+    fn entry_from_the_future_is_not_aged() {
+        let mut cache = DNSCache::new();
+        cache.set_records("www.example", QueryType::A, &referral_answer(), "example");
+        backdate(&cache, "www.example", QueryType::A, Instant::now() + Duration::from_secs(60));
+        let cached = cache.get_records("www.example", QueryType::A).unwrap();
+        assert_eq!(cached.answers[0].get_ttl(), 300);
+    }
+
+    #[test]
+    fn skips_rrsets_out_of_bailiwick() {
+        let mut cache = DNSCache::new();
+        let mut packet = referral_answer();
+        packet.addtional.push(DNSRecord::new_a("ns.other", Ipv4Addr::new(198, 51, 100, 1), 300));
+        cache.set_rrsets(&packet, "example");
+        assert!(cache.get_records("ns1.example", QueryType::A).is_some());
+        assert!(cache.get_records("ns.other", QueryType::A).is_none());
+    }
+
+    #[test]
+    fn cached_responses_leave_out_the_opt_record() {
+        let mut cache = DNSCache::new();
+        let mut packet = referral_answer();
+        packet.set_edns(0, 0);
+        cache.set_records("www.example", QueryType::A, &packet, "example");
+        let cached = cache.get_records("www.example", QueryType::A).unwrap();
+        assert_eq!(cached.addtional, vec![DNSRecord::new_a("ns1.example", Ipv4Addr::new(192, 0, 2, 53), 300)]);
+        assert_eq!(cached.edns_version(), None);
+    }
+
+    #[test]
+    fn every_spelling_of_a_name_hits_the_same_entry() {
+        let mut cache = DNSCache::new();
+        let mut packet = DNSPacket::new();
+        packet.answers.push(DNSRecord::new_a("Example.COM.", Ipv4Addr::new(192, 0, 2, 1), 300));
+        cache.set_records("Example.COM.", QueryType::A, &packet, "");
+        for spelling in ["example.com", "EXAMPLE.com", "example.com."].iter() {
+            assert_eq!(cache.get_records(spelling, QueryType::A).unwrap().answers.len(), 1);
+        }
+        assert_eq!(cache.map.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn entries_share_records_instead_of_copying_them() {
+        let mut cache = DNSCache::new();
+        cache.set_records("www.example", QueryType::A, &referral_answer(), "example");
+        let first = cache.get_entry("www.example", QueryType::A).unwrap();
+        assert_eq!(Arc::strong_count(&first.answers[0]), 2);
+        let second = cache.get_entry("www.example", QueryType::A).unwrap();
+        assert!(Arc::ptr_eq(&first.answers[0], &second.answers[0]));
+        assert_eq!(Arc::strong_count(&first.answers[0]), 3);
+        // The referral's NS RRset is cached on its own, sharing the same record
+        let ns = cache.get_entry("example", QueryType::NS).unwrap();
+        assert!(Arc::ptr_eq(&ns.answers[0], &first.authority[0]));
+    }
+
+    #[test]
+    fn ttl_zero_answer_purges_the_cached_entry() {
+        let mut cache = DNSCache::new();
+        cache.set_records("www.example", QueryType::A, &referral_answer(), "example");
+        let mut update = DNSPacket::new();
+        update.answers.push(DNSRecord::new_a("www.example", Ipv4Addr::new(192, 0, 2, 2), 0));
+        cache.set_records("www.example", QueryType::A, &update, "example");
+        assert!(cache.get_records("www.example", QueryType::A).is_none());
+        // The glue from the first response isn't touched
+        assert!(cache.get_records("ns1.example", QueryType::A).is_some());
+    }
+
+    fn dedup_key(id: u16) -> DedupKey {
+        let src = "192.0.2.10:5353".parse().unwrap();
+        (src, id, vec![("www.example".to_owned(), QueryType::A, QueryClass::IN)])
+    }
+
+    #[test]
+    fn retransmissions_join_the_pending_lookup() {
+        let dedup = ResponseDedup::new(Duration::from_secs(2));
+        let claim = match dedup.claim(dedup_key(1)) {
+            Dedup::Claimed(claim) => claim,
+            other => panic!("first copy not claimed: {:?}", other),
+        };
+        assert!(matches!(dedup.claim(dedup_key(1)), Dedup::InFlight));
+        // Another query id is another query
+        assert!(matches!(dedup.claim(dedup_key(2)), Dedup::Claimed(_)));
+
+        claim.complete(vec![1, 2, 3]);
+        assert!(matches!(dedup.claim(dedup_key(1)), Dedup::Sent(bytes) if bytes == vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn failed_lookup_releases_its_claim() {
+        let dedup = ResponseDedup::new(Duration::from_secs(2));
+        drop(dedup.claim(dedup_key(1)));
+        assert!(matches!(dedup.claim(dedup_key(1)), Dedup::Claimed(_)));
+    }
+
+    #[test]
+    fn responses_are_only_resent_within_the_window() {
+        let dedup = ResponseDedup::new(Duration::from_secs(0));
+        if let Dedup::Claimed(claim) = dedup.claim(dedup_key(1)) {
+            claim.complete(vec![1]);
+        }
+        assert!(matches!(dedup.claim(dedup_key(1)), Dedup::Claimed(_)));
+    }
+}
@@ -0,0 +1,398 @@
+use crate::authority::AuthorityStore;
+use crate::cache::ResponseDedup;
+use crate::metrics::Metrics;
+use crate::resolver::{QueryOptions, ReqProtocol};
+use crate::{
+    is_subdomain_of, normalize_name, reverse_name_to_ip, DNSPacket, DNSRecord, QueryClass,
+    QueryType, RCode,
+};
+use eyre::{eyre, Result};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    // Names under any of these suffixes are answered locally with `blocked_rcode`
+    // instead of being sent upstream.
+    pub blocked_suffixes: Vec<String>,
+    pub blocked_rcode: RCode,
+    // Policy limit on the wire length of accepted qnames, below the protocol's 255
+    pub max_qname_len: usize,
+    // Blocked A queries are answered with this address instead of `blocked_rcode`
+    pub sinkhole: Option<Ipv4Addr>,
+    // Never use AAAA glue or IPv6 nameserver addresses while recursing, and
+    // answer AAAA questions with NODATA instead of resolving them. Also keeps
+    // the server off the IPv6 wildcard address whatever `listen_ipv6` says.
+    pub disable_ipv6: bool,
+    // Every answered question is also sent to this resolver in the background
+    // and any difference from our own answer is logged.
+    pub mirror_server: Option<SocketAddr>,
+    // Maximum number of upstream queries a single client request may trigger,
+    // across referrals and nameserver lookups, before it fails with SERVFAIL.
+    pub query_budget: u32,
+    // How deeply nameserver names may be resolved within each other, guarding
+    // against referral loops between mutually delegating nameservers
+    pub max_recursion_depth: u32,
+    // Pick randomly among a delegation's not yet measured nameservers instead of
+    // always the first, spreading load across them. Measured ones go by SRTT.
+    pub randomize_ns: bool,
+    // TTL of every record the server synthesizes itself rather than resolving.
+    // Kept low so clients don't hold on to sinkholed answers for long.
+    pub local_answer_ttl: u32,
+    // Accept queue length of the TCP listener
+    pub tcp_backlog: i32,
+    // Length-prefixed DNS messages are small, so Nagle's algorithm only adds latency
+    pub tcp_nodelay: bool,
+    // Names answered authoritatively from local records instead of recursing
+    pub authority: AuthorityStore,
+    // (zone suffix, upstream) pairs: names under a zone are forwarded to its
+    // upstream resolver instead of being recursed, most specific zone first.
+    pub forward_zones: Vec<(String, SocketAddr)>,
+    // Upper bound on concurrent lookups made by `resolver::resolve_batch`
+    pub batch_concurrency: usize,
+    // Opt-in NXDOMAIN redirect: A/AAAA questions for names that don't exist are
+    // answered with this landing address instead. This hides real NXDOMAINs from
+    // clients (breaking search-domain fallback, mail delivery checks and the like),
+    // so it is off unless explicitly configured.
+    pub nxdomain_redirect: Option<IpAddr>,
+    // Where recursion starts, a.root-servers.net unless pointed at a test hierarchy
+    pub root_server: Ipv4Addr,
+    // Port nameservers are queried on. Only ever changed for test nameservers,
+    // which can't bind the privileged port 53.
+    pub nameserver_port: u16,
+    // PTR answers for the server's own addresses, given before recursing
+    pub local_ptr: HashMap<IpAddr, String>,
+    // When set, questions for any other type are refused instead of resolved
+    pub allowed_types: Option<HashSet<QueryType>>,
+    // Serve on the IPv6 wildcard address too, next to 0.0.0.0. Ignored when
+    // `disable_ipv6` is set.
+    pub listen_ipv6: bool,
+    // Also serve DNS over HTTP on port 2080, see `DNSHttpServer`. Off by default:
+    // the server speaks plain HTTP and is meant to sit behind a TLS proxy.
+    pub serve_doh: bool,
+    pub query_options: QueryOptions,
+    // Answer "localhost" and its subdomains with loopback addresses (and PTR
+    // questions for loopback addresses with "localhost") without going upstream,
+    // as RFC 6761 section 6.3 requires
+    pub answer_localhost: bool,
+    // Graceful degradation under load: one permit per resolution allowed to be
+    // in flight. Once all are taken, questions are answered from the cache or
+    // fail with SERVFAIL straight away instead of queuing behind the others.
+    pub lookup_slots: Option<Arc<Semaphore>>,
+    // Drop private and other bogon A/AAAA answers for recursively resolved names,
+    // which point at poisoning or a misconfigured zone. Forwarded zones are
+    // exempt, they often serve internal addresses on purpose.
+    pub filter_bogons: bool,
+    // Queries for these types, or for names under these zones, go to upstreams
+    // over TCP straight away instead of starting over UDP and being promoted to
+    // TCP on truncation. Meant for RRsets known to be large.
+    pub tcp_first_types: HashSet<QueryType>,
+    pub tcp_first_zones: Vec<String>,
+    // Most additional records (OPT aside) a response carries, to keep responses
+    // and their amplification potential small. Glue is dropped last.
+    pub max_additional: Option<usize>,
+    pub metrics: Metrics,
+    // StatsD server the metrics are pushed to every `statsd_interval`, with each
+    // name under `statsd_prefix`
+    pub statsd_server: Option<SocketAddr>,
+    pub statsd_prefix: String,
+    pub statsd_interval: Duration,
+    // UDP responses are kept this long per client, query id and question so
+    // retransmissions are answered without resolving again, and retransmissions
+    // of queries still being resolved are dropped. Off by default.
+    pub response_dedup: Option<ResponseDedup>,
+    // Answer to CHAOS class version.bind/version.server TXT questions. None by
+    // default, refusing them like every other CHAOS question rather than
+    // telling anyone who asks which release is running
+    pub chaos_version: Option<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            blocked_suffixes: Vec::new(),
+            blocked_rcode: RCode::NXDOMAIN,
+            max_qname_len: 255,
+            sinkhole: None,
+            disable_ipv6: false,
+            mirror_server: None,
+            query_budget: 32,
+            max_recursion_depth: 16,
+            randomize_ns: true,
+            local_answer_ttl: 30,
+            tcp_backlog: 1024,
+            tcp_nodelay: true,
+            authority: AuthorityStore::new(),
+            forward_zones: Vec::new(),
+            batch_concurrency: 8,
+            nxdomain_redirect: None,
+            root_server: Ipv4Addr::new(198, 41, 0, 4),
+            nameserver_port: 53,
+            local_ptr: HashMap::new(),
+            allowed_types: None,
+            listen_ipv6: true,
+            serve_doh: false,
+            query_options: QueryOptions::default(),
+            answer_localhost: true,
+            lookup_slots: None,
+            filter_bogons: false,
+            tcp_first_types: HashSet::new(),
+            tcp_first_zones: Vec::new(),
+            max_additional: None,
+            metrics: Metrics::new(),
+            statsd_server: None,
+            statsd_prefix: "diglett".to_owned(),
+            statsd_interval: Duration::from_secs(10),
+            response_dedup: None,
+            chaos_version: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    // The forwarded zone `qname` falls under and the upstream serving it
+    pub fn forwarder_for(&self, qname: &str) -> Option<(&str, SocketAddr)> {
+        self.forward_zones
+            .iter()
+            .filter(|(zone, _)| is_subdomain_of(qname, zone))
+            .max_by_key(|(zone, _)| normalize_name(zone).len())
+            .map(|(zone, server)| (zone.as_str(), *server))
+    }
+
+    // The landing record replacing an NXDOMAIN, if redirects are enabled and the
+    // landing address family matches the question
+    pub fn nxdomain_redirect_record(&self, qname: &str, q_type: QueryType) -> Option<DNSRecord> {
+        match (self.nxdomain_redirect?, q_type) {
+            (IpAddr::V4(addr), QueryType::A) => {
+                Some(DNSRecord::new_a(qname, addr, self.local_answer_ttl))
+            }
+            (IpAddr::V6(addr), QueryType::AAAA) => {
+                Some(DNSRecord::new_aaaa(qname, addr, self.local_answer_ttl))
+            }
+            _ => None,
+        }
+    }
+
+    // SOA for negative answers the server makes up itself, so clients can
+    // negatively cache them for `local_answer_ttl`
+    pub fn local_soa(&self, zone: &str) -> DNSRecord {
+        DNSRecord::SOA {
+            name: zone.to_owned(),
+            q_type: QueryType::SOA,
+            class: 1,
+            ttl: self.local_answer_ttl,
+            len: 0,
+            mname: "localhost".to_owned(),
+            rname: "nobody.invalid".to_owned(),
+            serial: 1,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: self.local_answer_ttl,
+        }
+    }
+
+    pub fn local_ptr_record(&self, qname: &str) -> Option<DNSRecord> {
+        let host = self.local_ptr.get(&reverse_name_to_ip(qname)?)?;
+        Some(DNSRecord::PTR {
+            name: qname.to_owned(),
+            q_type: QueryType::PTR,
+            class: 1,
+            ttl: self.local_answer_ttl,
+            len: 0,
+            host: host.clone(),
+        })
+    }
+
+    // The server's version for the CHAOS TXT questions tools like `dig` send
+    pub fn chaos_answer(&self, qname: &str, q_type: QueryType) -> Option<DNSRecord> {
+        let version = self.chaos_version.as_ref()?;
+        let qname = normalize_name(qname);
+        if q_type != QueryType::TXT || (qname != "version.bind" && qname != "version.server") {
+            return None;
+        }
+        Some(DNSRecord::TXT {
+            name: qname,
+            q_type: QueryType::TXT,
+            class: QueryClass::CH.to_num(),
+            ttl: 0,
+            len: 0,
+            data: vec![version.clone()],
+        })
+    }
+
+    // Answer for special-use loopback names, None for every other question
+    pub fn localhost_answer(&self, qname: &str, q_type: QueryType) -> Option<DNSPacket> {
+        if !self.answer_localhost {
+            return None;
+        }
+        let mut packet = DNSPacket::new();
+        packet.header.auth_answer = true;
+        if is_subdomain_of(qname, "localhost") {
+            match q_type {
+                QueryType::A => packet.answers.push(DNSRecord::new_a(
+                    qname,
+                    Ipv4Addr::LOCALHOST,
+                    self.local_answer_ttl,
+                )),
+                QueryType::AAAA => packet.answers.push(DNSRecord::new_aaaa(
+                    qname,
+                    Ipv6Addr::LOCALHOST,
+                    self.local_answer_ttl,
+                )),
+                _ => packet.authority.push(self.local_soa("localhost")),
+            }
+            return Some(packet);
+        }
+        match reverse_name_to_ip(qname) {
+            Some(addr) if addr.is_loopback() && q_type == QueryType::PTR => {
+                packet.answers.push(DNSRecord::PTR {
+                    name: qname.to_owned(),
+                    q_type: QueryType::PTR,
+                    class: 1,
+                    ttl: self.local_answer_ttl,
+                    len: 0,
+                    host: "localhost".to_owned(),
+                });
+                Some(packet)
+            }
+            _ => None,
+        }
+    }
+
+    // Protocol an upstream query for (qname, q_type) to `server` starts with.
+    // Servers with a TLS name are always queried over TLS, otherwise without a
+    // TCP preference it's `default`, the protocol the client asked us over.
+    pub fn upstream_protocol(
+        &self,
+        qname: &str,
+        q_type: QueryType,
+        server: SocketAddr,
+        default: ReqProtocol,
+    ) -> ReqProtocol {
+        if self.query_options.tls_server_names.contains_key(&server) {
+            return ReqProtocol::DoT;
+        }
+        if self.tcp_first_types.contains(&q_type)
+            || self
+                .tcp_first_zones
+                .iter()
+                .any(|zone| is_subdomain_of(qname, zone))
+        {
+            return ReqProtocol::TCP;
+        }
+        default
+    }
+
+    pub fn is_type_allowed(&self, q_type: QueryType) -> bool {
+        match &self.allowed_types {
+            Some(types) => types.contains(&q_type),
+            None => true,
+        }
+    }
+
+    pub fn is_blocked(&self, qname: &str) -> bool {
+        self.blocked_suffixes
+            .iter()
+            .any(|suffix| is_subdomain_of(qname, suffix))
+    }
+
+    // Rejects settings the server can't run with
+    pub fn validate(&self) -> Result<()> {
+        if self
+            .lookup_slots
+            .as_ref()
+            .map(|slots| slots.available_permits())
+            == Some(0)
+        {
+            return Err(eyre!("lookup_slots must allow at least one lookup"));
+        }
+        if self.statsd_interval == Duration::from_secs(0) {
+            return Err(eyre!("statsd_interval must be above 0"));
+        }
+        if let Some((min, max)) = self.query_options.source_ports {
+            // Port 0 would ask the OS for any port, outside the range
+            if min == 0 || min > max {
+                return Err(eyre!(
+                    "source_ports must be a range of ports from 1 to 65535 with min <= max, not {}-{}",
+                    min,
+                    max
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_names_under_suffixes_only() {
+        let config = ServerConfig {
+            blocked_suffixes: vec!["ads.example".to_owned(), "internal.".to_owned()],
+            ..ServerConfig::default()
+        };
+        assert!(config.is_blocked("ads.example"));
+        assert!(config.is_blocked("Tracker.ADS.example."));
+        assert!(config.is_blocked("svc.internal"));
+        assert!(!config.is_blocked("notinternal"));
+        assert!(!config.is_blocked("example"));
+    }
+
+    #[test]
+    fn version_is_only_revealed_when_configured() {
+        let config = ServerConfig::default();
+        assert!(config
+            .chaos_answer("version.bind", QueryType::TXT)
+            .is_none());
+        let config = ServerConfig {
+            chaos_version: Some("resolver".to_owned()),
+            ..ServerConfig::default()
+        };
+        match config.chaos_answer("VERSION.bind.", QueryType::TXT) {
+            Some(DNSRecord::TXT { data, .. }) => assert_eq!(data, vec!["resolver".to_owned()]),
+            other => panic!("unexpected answer {:?}", other),
+        }
+        assert!(config.chaos_answer("version.bind", QueryType::A).is_none());
+    }
+
+    #[test]
+    fn validates_source_port_ranges() {
+        let with_ports = |source_ports| {
+            let mut config = ServerConfig::default();
+            config.query_options.source_ports = source_ports;
+            config
+        };
+        assert!(with_ports(Some((2000, 1000))).validate().is_err());
+        assert!(with_ports(Some((0, 1000))).validate().is_err());
+        assert!(with_ports(Some((65535, 65535))).validate().is_ok());
+        assert!(with_ports(None).validate().is_ok());
+    }
+
+    #[test]
+    fn large_types_and_zones_start_over_tcp() {
+        let mut config = ServerConfig::default();
+        config.tcp_first_types.insert(QueryType::CDNSKEY);
+        config.tcp_first_zones.push("big.example".to_owned());
+        let server = "192.0.2.53:53".parse().unwrap();
+        let protocol =
+            |qname, q_type| config.upstream_protocol(qname, q_type, server, ReqProtocol::UDP);
+        assert!(matches!(
+            protocol("example", QueryType::CDNSKEY),
+            ReqProtocol::TCP
+        ));
+        assert!(matches!(
+            protocol("www.big.example", QueryType::A),
+            ReqProtocol::TCP
+        ));
+        assert!(matches!(
+            protocol("www.example", QueryType::A),
+            ReqProtocol::UDP
+        ));
+    }
+}
@@ -0,0 +1,323 @@
+// Presentation format in the layout `dig` prints, for logs and debugging
+use crate::nsec3::base32hex_encode;
+use crate::{DNSPacket, DNSQuestion, DNSRecord, QueryClass, QueryType};
+use std::fmt;
+
+fn fqdn(name: &str) -> String {
+    if name.ends_with('.') {
+        name.to_owned()
+    } else {
+        format!("{}.", name)
+    }
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02X}", byte)).collect()
+}
+
+// Window/bitmap type lists of NSEC3 and CSYNC (RFC 5155 section 3.2.1)
+fn bitmap_types(bitmap: &[u8]) -> Vec<String> {
+    let mut types = Vec::new();
+    let mut pos = 0;
+    while pos + 2 <= bitmap.len() {
+        let window = bitmap[pos] as u16;
+        let len = bitmap[pos + 1] as usize;
+        let octets = &bitmap[(pos + 2).min(bitmap.len())..(pos + 2 + len).min(bitmap.len())];
+        for (i, octet) in octets.iter().enumerate() {
+            for bit in 0..8 {
+                if octet & (0x80 >> bit) != 0 {
+                    let num = window * 256 + (i * 8 + bit) as u16;
+                    types.push(QueryType::from_num(num).to_string());
+                }
+            }
+        }
+        pos += 2 + len;
+    }
+    types
+}
+
+impl fmt::Display for QueryType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            // Types without a mnemonic as in RFC 3597 section 5
+            QueryType::UNKNOWN(num) => write!(f, "TYPE{}", num),
+            _ => write!(f, "{:?}", self),
+        }
+    }
+}
+
+impl fmt::Display for QueryClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QueryClass::UNKNOWN(num) => write!(f, "CLASS{}", num),
+            _ => write!(f, "{:?}", self),
+        }
+    }
+}
+
+impl fmt::Display for DNSQuestion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            ";{}\t\t{}\t{}",
+            fqdn(&self.name),
+            self.class,
+            self.q_type
+        )
+    }
+}
+
+impl fmt::Display for DNSRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t",
+            fqdn(self.get_name()),
+            self.get_ttl(),
+            QueryClass::from_num(self.get_class()),
+            self.get_type()
+        )?;
+        match self {
+            DNSRecord::UNKNOWN { data, .. } => write!(f, "\\# {} {}", data.len(), hex(data)),
+            DNSRecord::A { addr, .. } => write!(f, "{}", addr),
+            DNSRecord::AAAA { addr, .. } => write!(f, "{}", addr),
+            DNSRecord::NS { host, .. }
+            | DNSRecord::CNAME { host, .. }
+            | DNSRecord::PTR { host, .. } => write!(f, "{}", fqdn(host)),
+            DNSRecord::MX { priority, host, .. } => write!(f, "{} {}", priority, fqdn(host)),
+            DNSRecord::SOA {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ..
+            } => write!(
+                f,
+                "{} {} {} {} {} {} {}",
+                fqdn(mname),
+                fqdn(rname),
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum
+            ),
+            DNSRecord::CDS {
+                key_tag,
+                algorithm,
+                digest_type,
+                digest,
+                ..
+            } => write!(
+                f,
+                "{} {} {} {}",
+                key_tag,
+                algorithm,
+                digest_type,
+                hex(digest)
+            ),
+            DNSRecord::CDNSKEY {
+                flags,
+                protocol,
+                algorithm,
+                public_key,
+                ..
+            } => write!(
+                f,
+                "{} {} {} {}",
+                flags,
+                protocol,
+                algorithm,
+                base64::encode(public_key)
+            ),
+            DNSRecord::OPT { options, .. } => {
+                let options = options
+                    .iter()
+                    .map(|(code, data)| format!("{}:{}", code, hex(data)))
+                    .collect::<Vec<String>>();
+                write!(f, "{}", options.join(" "))
+            }
+            DNSRecord::CERT {
+                cert_type,
+                key_tag,
+                algorithm,
+                certificate,
+                ..
+            } => write!(
+                f,
+                "{} {} {} {}",
+                cert_type,
+                key_tag,
+                algorithm,
+                base64::encode(certificate)
+            ),
+            DNSRecord::NSEC3 {
+                hash_algorithm,
+                flags,
+                iterations,
+                salt,
+                next_hashed,
+                type_bitmaps,
+                ..
+            } => {
+                let salt = if salt.is_empty() {
+                    "-".to_owned()
+                } else {
+                    hex(salt)
+                };
+                write!(
+                    f,
+                    "{} {} {} {} {}",
+                    hash_algorithm,
+                    flags,
+                    iterations,
+                    salt,
+                    base32hex_encode(next_hashed)
+                )?;
+                for q_type in bitmap_types(type_bitmaps) {
+                    write!(f, " {}", q_type)?;
+                }
+                Ok(())
+            }
+            DNSRecord::EUI48 { address, .. } => {
+                let octets = address.iter().map(|octet| format!("{:02x}", octet));
+                write!(f, "{}", octets.collect::<Vec<String>>().join("-"))
+            }
+            DNSRecord::EUI64 { address, .. } => {
+                let octets = address.iter().map(|octet| format!("{:02x}", octet));
+                write!(f, "{}", octets.collect::<Vec<String>>().join("-"))
+            }
+            DNSRecord::WKS {
+                addr,
+                protocol,
+                bitmap,
+                ..
+            } => {
+                write!(f, "{} {}", addr, protocol)?;
+                for (i, octet) in bitmap.iter().enumerate() {
+                    for bit in 0..8 {
+                        if octet & (0x80 >> bit) != 0 {
+                            write!(f, " {}", i * 8 + bit)?;
+                        }
+                    }
+                }
+                Ok(())
+            }
+            DNSRecord::TXT { data, .. } => {
+                let strings = data
+                    .iter()
+                    .map(|string| {
+                        format!("\"{}\"", string.replace('\\', "\\\\").replace('"', "\\\""))
+                    })
+                    .collect::<Vec<String>>();
+                write!(f, "{}", strings.join(" "))
+            }
+            DNSRecord::CSYNC {
+                soa_serial,
+                flags,
+                type_bitmap,
+                ..
+            } => {
+                write!(f, "{} {}", soa_serial, flags)?;
+                for q_type in bitmap_types(type_bitmap) {
+                    write!(f, " {}", q_type)?;
+                }
+                Ok(())
+            }
+            DNSRecord::CAA {
+                flags, tag, value, ..
+            } => write!(f, "{} {} \"{}\"", flags, tag, value.replace('"', "\\\"")),
+            DNSRecord::ZONEMD {
+                serial,
+                scheme,
+                hash_algorithm,
+                digest,
+                ..
+            } => write!(
+                f,
+                "{} {} {} {}",
+                serial,
+                scheme,
+                hash_algorithm,
+                hex(digest)
+            ),
+        }
+    }
+}
+
+impl fmt::Display for DNSPacket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let header = &self.header;
+        writeln!(
+            f,
+            ";; ->>HEADER<<- opcode: {:?}, status: {:?}, id: {}",
+            header.opcode, header.res_code, header.id
+        )?;
+        let flags = [
+            (header.query_response, "qr"),
+            (header.auth_answer, "aa"),
+            (header.truncated_msg, "tc"),
+            (header.recur_desired, "rd"),
+            (header.recur_available, "ra"),
+            (header.authentic_data, "ad"),
+            (header.checking_disabled, "cd"),
+        ]
+        .iter()
+        .filter(|(set, _)| *set)
+        .map(|(_, flag)| *flag)
+        .collect::<Vec<&str>>();
+        // The OPT record is shown as its own pseudosection, not as an additional record
+        let additional = self
+            .addtional
+            .iter()
+            .filter(|record| record.get_type() != QueryType::OPT)
+            .collect::<Vec<&DNSRecord>>();
+        writeln!(
+            f,
+            ";; flags: {}; QUERY: {}, ANSWER: {}, AUTHORITY: {}, ADDITIONAL: {}",
+            flags.join(" "),
+            self.questions.len(),
+            self.answers.len(),
+            self.authority.len(),
+            self.addtional.len()
+        )?;
+        if let Some(opt) = self.get_opt() {
+            writeln!(f)?;
+            writeln!(f, ";; OPT PSEUDOSECTION:")?;
+            writeln!(
+                f,
+                "; EDNS: version: {}, flags:{}; udp: {}",
+                (opt.get_ttl() >> 16) & 0xFF,
+                if self.dnssec_ok() { " do" } else { "" },
+                opt.get_class()
+            )?;
+        }
+        if !self.questions.is_empty() {
+            writeln!(f)?;
+            writeln!(f, ";; QUESTION SECTION:")?;
+            for question in &self.questions {
+                writeln!(f, "{}", question)?;
+            }
+        }
+        for (heading, records) in [
+            ("ANSWER", self.answers.iter().collect::<Vec<&DNSRecord>>()),
+            ("AUTHORITY", self.authority.iter().collect()),
+            ("ADDITIONAL", additional),
+        ]
+        .iter()
+        {
+            if records.is_empty() {
+                continue;
+            }
+            writeln!(f)?;
+            writeln!(f, ";; {} SECTION:", heading)?;
+            for record in records {
+                writeln!(f, "{}", record)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,28 +1,242 @@
+pub mod answer;
+pub mod authority;
 pub mod buffer;
 pub mod cache;
+pub mod config;
+mod display;
+pub mod metrics;
+pub mod nsec3;
+pub mod resolver;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod upstream;
+pub mod zonemd;
 use buffer::*;
-use eyre::Result;
-use std::net::{Ipv4Addr, Ipv6Addr};
+use eyre::{eyre, Result};
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
+// Far above what any legitimate response carries, even over TCP
+pub const DEFAULT_MAX_RECORDS: usize = 4096;
+
+pub const CLASS_IN: u16 = 1;
+
+pub const EDNS_OPTION_EDE: u16 = 15;
+// The only EDNS version defined so far (RFC 6891 section 6.1.3)
+pub const EDNS_VERSION: u8 = 0;
+// DNSSEC OK, the client wants DNSSEC records (RFC 3225)
+pub const EDNS_FLAG_DO: u16 = 0x8000;
+// Extended rcode, its upper 8 bits travel in the OPT record's TTL
+pub const RCODE_BADVERS: u16 = 16;
+// Plain DNS over UDP (RFC 1035 section 2.3.4), the floor for any EDNS size too
+pub const MIN_UDP_PAYLOAD: u16 = 512;
+// Largest UDP response sent whatever a client advertises
+pub const MAX_UDP_PAYLOAD: u16 = 4096;
+// Advertised in our OPT records, requests are received into a 512 byte ArrayBuffer
+pub const SERVER_UDP_PAYLOAD: u16 = MIN_UDP_PAYLOAD;
+
+// Extended DNS Error info codes (RFC 8914 section 4)
+pub const EDE_DNSSEC_BOGUS: u16 = 6;
+pub const EDE_BLOCKED: u16 = 15;
+pub const EDE_NO_REACHABLE_AUTHORITY: u16 = 22;
+
+// One-off blocking recursive lookup, see `resolver::Resolver` for anything more
+pub fn resolve(qname: &str, q_type: QueryType) -> Result<DNSPacket> {
+    resolver::Resolver::recursive().resolve_blocking(qname, q_type)
+}
+
+// Names are case-insensitive and may or may not carry the root's trailing dot
+pub fn normalize_name(name: &str) -> String {
+    name.trim_end_matches('.').to_lowercase()
+}
+
+// Length of the name in uncompressed wire format: a length byte per label,
+// the label data and the terminating null label
+pub fn name_wire_len(name: &str) -> usize {
+    let name = name.trim_end_matches('.');
+    if name.is_empty() {
+        return 1;
+    }
+    name.len() + 2
+}
+
+// Label-wise suffix match, so "svc.internal" is under "internal" but "notinternal" is not.
+pub fn is_subdomain_of(name: &str, zone: &str) -> bool {
+    let name = normalize_name(name);
+    let zone = normalize_name(zone);
+    if zone.is_empty() {
+        return true;
+    }
+    name == zone || name.ends_with(&format!(".{}", zone))
+}
+
+// Address a reverse lookup name such as "4.3.2.1.in-addr.arpa" or the 32
+// nibble labels under "ip6.arpa" stands for
+pub fn reverse_name_to_ip(name: &str) -> Option<IpAddr> {
+    let name = normalize_name(name);
+    if let Some(labels) = name.strip_suffix(".in-addr.arpa") {
+        let mut octets = labels
+            .split('.')
+            .map(|label| label.parse::<u8>().ok())
+            .collect::<Option<Vec<u8>>>()?;
+        if octets.len() != 4 {
+            return None;
+        }
+        octets.reverse();
+        return Some(IpAddr::V4(Ipv4Addr::new(
+            octets[0], octets[1], octets[2], octets[3],
+        )));
+    }
+    let labels = name.strip_suffix(".ip6.arpa")?;
+    let nibbles = labels
+        .split('.')
+        .rev()
+        .map(|label| match label.len() {
+            1 => u8::from_str_radix(label, 16).ok(),
+            _ => None,
+        })
+        .collect::<Option<Vec<u8>>>()?;
+    if nibbles.len() != 32 {
+        return None;
+    }
+    let mut octets = [0; 16];
+    for (i, pair) in nibbles.chunks(2).enumerate() {
+        octets[i] = pair[0] << 4 | pair[1];
+    }
+    Some(IpAddr::V6(Ipv6Addr::from(octets)))
+}
+
+// RFC 1982 serial number arithmetic: `a` is newer than `b` if it is ahead by
+// less than half the number space, so serials keep ordering across wraparound
+pub fn serial_newer(a: u32, b: u32) -> bool {
+    a != b && a.wrapping_sub(b) < 1 << 31
+}
+
+// Private, loopback, link-local, documentation and other reserved addresses
+// that no name on the public internet should resolve to
+pub fn is_bogon(addr: IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(addr) => {
+            let octets = addr.octets();
+            addr.is_private()
+                || addr.is_loopback()
+                || addr.is_link_local()
+                || addr.is_unspecified()
+                || addr.is_broadcast()
+                || addr.is_documentation()
+                || addr.is_multicast()
+                || octets[0] == 0
+                // Shared address space for carrier-grade NAT (RFC 6598)
+                || (octets[0] == 100 && octets[1] & 0xC0 == 64)
+                // Reserved for future use (RFC 1112 section 4)
+                || octets[0] >= 240
+        }
+        IpAddr::V6(addr) => {
+            let segments = addr.segments();
+            if let Some(mapped) = addr.to_ipv4() {
+                // IPv4-mapped (::ffff:0:0/96), checked by the embedded address
+                if segments[5] == 0xFFFF {
+                    return is_bogon(IpAddr::V4(mapped));
+                }
+            }
+            addr.is_loopback()
+                || addr.is_unspecified()
+                || addr.is_multicast()
+                // Unique local (fc00::/7) and link-local (fe80::/10)
+                || segments[0] & 0xFE00 == 0xFC00
+                || segments[0] & 0xFFC0 == 0xFE80
+                // Documentation (2001:db8::/32)
+                || (segments[0] == 0x2001 && segments[1] == 0x0DB8)
+        }
+    }
+}
+
+// The full 12 bit rcode, its upper 8 bits travel in the OPT record's TTL
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum RCode {
+    UNKNOWN(u16),
     NOERROR,
     FORMERR,
     SERVFAIL,
     NXDOMAIN,
     NOTIMP,
     REFUSED,
+    YXDOMAIN,
+    YXRRSET,
+    NXRRSET,
+    NOTAUTH,
+    NOTZONE,
+    // Extended only, needs an OPT record
+    BADVERS,
 }
 
 impl RCode {
-    fn from_num(num: usize) -> RCode {
+    fn to_num(self) -> u16 {
+        match self {
+            RCode::UNKNOWN(code) => code,
+            RCode::NOERROR => 0,
+            RCode::FORMERR => 1,
+            RCode::SERVFAIL => 2,
+            RCode::NXDOMAIN => 3,
+            RCode::NOTIMP => 4,
+            RCode::REFUSED => 5,
+            RCode::YXDOMAIN => 6,
+            RCode::YXRRSET => 7,
+            RCode::NXRRSET => 8,
+            RCode::NOTAUTH => 9,
+            RCode::NOTZONE => 10,
+            RCode::BADVERS => RCODE_BADVERS,
+        }
+    }
+    fn from_num(num: u16) -> RCode {
         match num {
+            0 => RCode::NOERROR,
             1 => RCode::FORMERR,
             2 => RCode::SERVFAIL,
             3 => RCode::NXDOMAIN,
             4 => RCode::NOTIMP,
             5 => RCode::REFUSED,
-            _ => RCode::NOERROR,
+            6 => RCode::YXDOMAIN,
+            7 => RCode::YXRRSET,
+            8 => RCode::NXRRSET,
+            9 => RCode::NOTAUTH,
+            10 => RCode::NOTZONE,
+            RCODE_BADVERS => RCode::BADVERS,
+            _ => RCode::UNKNOWN(num),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OpCode {
+    UNKNOWN(u8),
+    QUERY,
+    // Inverse query, obsoleted by RFC 3425
+    IQUERY,
+    STATUS,
+    NOTIFY,
+    UPDATE,
+}
+
+impl OpCode {
+    fn to_num(self) -> u8 {
+        match self {
+            Self::UNKNOWN(code) => code,
+            Self::QUERY => 0,
+            Self::IQUERY => 1,
+            Self::STATUS => 2,
+            Self::NOTIFY => 4,
+            Self::UPDATE => 5,
+        }
+    }
+    fn from_num(num: u8) -> Self {
+        match num {
+            0 => Self::QUERY,
+            1 => Self::IQUERY,
+            2 => Self::STATUS,
+            4 => Self::NOTIFY,
+            5 => Self::UPDATE,
+            _ => Self::UNKNOWN(num),
         }
     }
 }
@@ -31,12 +245,15 @@ impl RCode {
 pub struct DNSHeader {
     pub id: u16,
     pub query_response: bool,
-    pub opcode: u8, // We only need 4 bits of these 8
+    pub opcode: OpCode,
     pub auth_answer: bool,
     pub truncated_msg: bool,
     pub recur_desired: bool,
     pub recur_available: bool,
-    pub z_res: bool, // Actually 3 bits, ignoring them for now
+    pub z: bool,
+    // DNSSEC bits (RFC 4035 section 3.2)
+    pub authentic_data: bool,
+    pub checking_disabled: bool,
     pub res_code: RCode,
 
     pub q_count: u16,
@@ -45,17 +262,25 @@ pub struct DNSHeader {
     pub ad_count: u16,
 }
 
+impl Default for DNSHeader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl DNSHeader {
     pub fn new() -> DNSHeader {
         DNSHeader {
             id: 0,
             query_response: false,
-            opcode: 0,
+            opcode: OpCode::QUERY,
             auth_answer: false,
             truncated_msg: false,
             recur_desired: false,
             recur_available: false,
-            z_res: false,
+            z: false,
+            authentic_data: false,
+            checking_disabled: false,
             res_code: RCode::NOERROR,
             q_count: 0,
             an_count: 0,
@@ -68,13 +293,16 @@ impl DNSHeader {
         self.id = buf.read_u16()?;
         let flags = buf.read_u16()?;
         self.query_response = (flags & (1 << 15)) > 0;
-        self.opcode = (flags >> 11) as u8 & 0xF;
+        self.opcode = OpCode::from_num((flags >> 11) as u8 & 0xF);
         self.auth_answer = (flags & (1 << 10)) > 0;
         self.truncated_msg = (flags & (1 << 9)) > 0;
         self.recur_desired = (flags & (1 << 8)) > 0;
         self.recur_available = (flags & (1 << 7)) > 0;
-        self.z_res = (flags & (7 << 4)) > 0;
-        self.res_code = RCode::from_num((flags & 0xF) as usize);
+        self.z = (flags & (1 << 6)) > 0;
+        self.authentic_data = (flags & (1 << 5)) > 0;
+        self.checking_disabled = (flags & (1 << 4)) > 0;
+        // Only the lower 4 bits, `DNSPacket` adds the OPT record's upper bits
+        self.res_code = RCode::from_num(flags & 0xF);
         self.q_count = buf.read_u16()?;
         self.an_count = buf.read_u16()?;
         self.ns_count = buf.read_u16()?;
@@ -86,13 +314,15 @@ impl DNSHeader {
         buf.write_u16(self.id)?;
         buf.write_u16(
             ((self.query_response as u16) << 15)
-                | ((self.opcode as u16) << 11)
+                | ((self.opcode.to_num() as u16) << 11)
                 | ((self.auth_answer as u16) << 10)
                 | ((self.truncated_msg as u16) << 9)
                 | ((self.recur_desired as u16) << 8)
                 | ((self.recur_available as u16) << 7)
-                | ((self.z_res as u16) << 4)
-                | self.res_code as u16,
+                | ((self.z as u16) << 6)
+                | ((self.authentic_data as u16) << 5)
+                | ((self.checking_disabled as u16) << 4)
+                | (self.res_code.to_num() & 0xF),
         )?;
         buf.write_u16(self.q_count)?;
         buf.write_u16(self.an_count)?;
@@ -109,20 +339,49 @@ pub enum QueryType {
     NS,
     CNAME,
     SOA,
+    WKS,
+    PTR,
     MX,
+    TXT,
     AAAA,
+    CERT,
+    OPT,
+    NSEC3,
+    CDS,
+    CDNSKEY,
+    CSYNC,
+    ZONEMD,
+    EUI48,
+    EUI64,
+    // QTYPE only, asks for every record at the name
+    ANY,
+    CAA,
 }
 
 impl QueryType {
-    fn to_num(&self) -> u16 {
-        match *self {
+    fn to_num(self) -> u16 {
+        match self {
             Self::UNKNOWN(code) => code,
             Self::A => 1,
             Self::NS => 2,
             Self::CNAME => 5,
             Self::SOA => 6,
+            Self::WKS => 11,
+            Self::PTR => 12,
             Self::MX => 15,
+            Self::TXT => 16,
             Self::AAAA => 28,
+            Self::CERT => 37,
+            Self::OPT => 41,
+            Self::NSEC3 => 50,
+            Self::CDS => 59,
+            Self::CDNSKEY => 60,
+            Self::CSYNC => 62,
+            Self::ZONEMD => 63,
+            Self::EUI48 => 108,
+            Self::EUI64 => 109,
+            Self::ANY => 255,
+            Self::CAA => 257,
         }
     }
     fn from_num(num: u16) -> Self {
@@ -131,8 +390,54 @@ impl QueryType {
             2 => Self::NS,
             5 => Self::CNAME,
             6 => Self::SOA,
+            11 => Self::WKS,
+            12 => Self::PTR,
             15 => Self::MX,
+            16 => Self::TXT,
             28 => Self::AAAA,
+            37 => Self::CERT,
+            41 => Self::OPT,
+            50 => Self::NSEC3,
+            59 => Self::CDS,
+            60 => Self::CDNSKEY,
+            62 => Self::CSYNC,
+            63 => Self::ZONEMD,
+            108 => Self::EUI48,
+            109 => Self::EUI64,
+            255 => Self::ANY,
+            257 => Self::CAA,
+            _ => Self::UNKNOWN(num),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Hash, Copy)]
+pub enum QueryClass {
+    UNKNOWN(u16),
+    IN,
+    // CHAOS, nowadays only used to ask servers about themselves, e.g. version.bind
+    CH,
+    HS,
+    // QCLASS only, matches every class
+    ANY,
+}
+
+impl QueryClass {
+    fn to_num(self) -> u16 {
+        match self {
+            Self::UNKNOWN(code) => code,
+            Self::IN => 1,
+            Self::CH => 3,
+            Self::HS => 4,
+            Self::ANY => 255,
+        }
+    }
+    fn from_num(num: u16) -> Self {
+        match num {
+            1 => Self::IN,
+            3 => Self::CH,
+            4 => Self::HS,
+            255 => Self::ANY,
             _ => Self::UNKNOWN(num),
         }
     }
@@ -142,25 +447,34 @@ impl QueryType {
 pub struct DNSQuestion {
     pub name: String,
     pub q_type: QueryType,
+    pub class: QueryClass,
 }
 
 impl DNSQuestion {
     pub fn new(name: String, q_type: QueryType) -> Self {
-        Self { name, q_type }
+        Self {
+            name,
+            q_type,
+            class: QueryClass::IN,
+        }
     }
 
     pub fn read<T: PacketBufferTrait>(buf: &mut T) -> Result<DNSQuestion> {
         let mut name = String::new();
         buf.read_qname(&mut name)?;
         let q_type = QueryType::from_num(buf.read_u16()?);
-        buf.read_u16()?;
-        Ok(DNSQuestion { name, q_type })
+        let class = QueryClass::from_num(buf.read_u16()?);
+        Ok(DNSQuestion {
+            name,
+            q_type,
+            class,
+        })
     }
 
     pub fn write<T: PacketBufferTrait>(&self, buf: &mut T) -> Result<()> {
         buf.write_qname(&self.name)?;
         buf.write_u16(self.q_type.to_num())?;
-        buf.write_u16(1 as u16)?;
+        buf.write_u16(self.class.to_num())?;
         Ok(())
     }
 }
@@ -173,6 +487,8 @@ pub enum DNSRecord {
         class: u16,
         ttl: u32,
         len: u16,
+        // Opaque RDATA, written back as it came (RFC 3597)
+        data: Vec<u8>,
     },
     A {
         name: String,
@@ -229,9 +545,185 @@ pub enum DNSRecord {
         expire: u32,
         minimum: u32,
     },
+    CDS {
+        name: String,
+        q_type: QueryType,
+        class: u16,
+        ttl: u32,
+        len: u16,
+        key_tag: u16,
+        algorithm: u8,
+        digest_type: u8,
+        digest: Vec<u8>,
+    },
+    CDNSKEY {
+        name: String,
+        q_type: QueryType,
+        class: u16,
+        ttl: u32,
+        len: u16,
+        flags: u16,
+        protocol: u8,
+        algorithm: u8,
+        public_key: Vec<u8>,
+    },
+    OPT {
+        name: String,
+        q_type: QueryType,
+        class: u16,
+        ttl: u32,
+        len: u16,
+        options: Vec<(u16, Vec<u8>)>,
+    },
+    CERT {
+        name: String,
+        q_type: QueryType,
+        class: u16,
+        ttl: u32,
+        len: u16,
+        cert_type: u16,
+        key_tag: u16,
+        algorithm: u8,
+        certificate: Vec<u8>,
+    },
+    NSEC3 {
+        name: String,
+        q_type: QueryType,
+        class: u16,
+        ttl: u32,
+        len: u16,
+        hash_algorithm: u8,
+        flags: u8,
+        iterations: u16,
+        salt: Vec<u8>,
+        next_hashed: Vec<u8>,
+        type_bitmaps: Vec<u8>,
+    },
+    EUI48 {
+        name: String,
+        q_type: QueryType,
+        class: u16,
+        ttl: u32,
+        len: u16,
+        address: [u8; 6],
+    },
+    EUI64 {
+        name: String,
+        q_type: QueryType,
+        class: u16,
+        ttl: u32,
+        len: u16,
+        address: [u8; 8],
+    },
+    WKS {
+        name: String,
+        q_type: QueryType,
+        class: u16,
+        ttl: u32,
+        len: u16,
+        addr: Ipv4Addr,
+        protocol: u8,
+        bitmap: Vec<u8>,
+    },
+    TXT {
+        name: String,
+        q_type: QueryType,
+        class: u16,
+        ttl: u32,
+        len: u16,
+        data: Vec<String>,
+    },
+    PTR {
+        name: String,
+        q_type: QueryType,
+        class: u16,
+        ttl: u32,
+        len: u16,
+        host: String,
+    },
+    CSYNC {
+        name: String,
+        q_type: QueryType,
+        class: u16,
+        ttl: u32,
+        len: u16,
+        soa_serial: u32,
+        flags: u16,
+        type_bitmap: Vec<u8>,
+    },
+    ZONEMD {
+        name: String,
+        q_type: QueryType,
+        class: u16,
+        ttl: u32,
+        len: u16,
+        serial: u32,
+        scheme: u8,
+        hash_algorithm: u8,
+        digest: Vec<u8>,
+    },
+    CAA {
+        name: String,
+        q_type: QueryType,
+        class: u16,
+        ttl: u32,
+        len: u16,
+        flags: u8,
+        tag: String,
+        value: String,
+    },
+}
+
+// Equal records may still differ in `len`: the RDLENGTH read off the wire
+// depends on whether the sender compressed the names inside the RDATA
+impl PartialEq for DNSRecord {
+    fn eq(&self, other: &DNSRecord) -> bool {
+        self.get_name() == other.get_name()
+            && self.get_type() == other.get_type()
+            && self.get_class() == other.get_class()
+            && self.get_ttl() == other.get_ttl()
+            && self.rdata_eq(other)
+    }
+}
+
+impl Eq for DNSRecord {}
+
+// Bytes of RDATA left after its `fixed` leading bytes, an error when RDLENGTH
+// can't even hold those instead of reading into the next record
+fn variable_rdata_len(q_type: QueryType, len: u16, fixed: usize) -> Result<usize> {
+    (len as usize).checked_sub(fixed).ok_or_else(|| {
+        eyre!(
+            "{:?} RDATA of {} bytes is shorter than its {} byte fixed part",
+            q_type,
+            len,
+            fixed
+        )
+    })
 }
 
 impl DNSRecord {
+    pub fn new_a(name: &str, addr: Ipv4Addr, ttl: u32) -> DNSRecord {
+        DNSRecord::A {
+            name: name.to_owned(),
+            q_type: QueryType::A,
+            class: 1,
+            ttl,
+            len: 4,
+            addr,
+        }
+    }
+
+    pub fn new_aaaa(name: &str, addr: Ipv6Addr, ttl: u32) -> DNSRecord {
+        DNSRecord::AAAA {
+            name: name.to_owned(),
+            q_type: QueryType::AAAA,
+            class: 1,
+            ttl,
+            len: 16,
+            addr,
+        }
+    }
+
     pub fn get_ttl(&self) -> u32 {
         match *self {
             DNSRecord::A { ttl, .. } => ttl,
@@ -239,10 +731,382 @@ impl DNSRecord {
             DNSRecord::CNAME { ttl, .. } => ttl,
             DNSRecord::SOA { ttl, .. } => ttl,
             DNSRecord::MX { ttl, .. } => ttl,
-            DNSRecord::NS { ttl, .. } => ttl, 
+            DNSRecord::NS { ttl, .. } => ttl,
+            DNSRecord::CDS { ttl, .. } => ttl,
+            DNSRecord::CDNSKEY { ttl, .. } => ttl,
+            DNSRecord::OPT { ttl, .. } => ttl,
+            DNSRecord::CERT { ttl, .. } => ttl,
+            DNSRecord::NSEC3 { ttl, .. } => ttl,
+            DNSRecord::EUI48 { ttl, .. } => ttl,
+            DNSRecord::EUI64 { ttl, .. } => ttl,
+            DNSRecord::WKS { ttl, .. } => ttl,
+            DNSRecord::TXT { ttl, .. } => ttl,
+            DNSRecord::PTR { ttl, .. } => ttl,
+            DNSRecord::CSYNC { ttl, .. } => ttl,
+            DNSRecord::ZONEMD { ttl, .. } => ttl,
+            DNSRecord::CAA { ttl, .. } => ttl,
             DNSRecord::UNKNOWN { ttl, .. } => ttl,
         }
     }
+
+    pub fn get_name(&self) -> &str {
+        match *self {
+            DNSRecord::A { ref name, .. } => name,
+            DNSRecord::AAAA { ref name, .. } => name,
+            DNSRecord::CNAME { ref name, .. } => name,
+            DNSRecord::SOA { ref name, .. } => name,
+            DNSRecord::MX { ref name, .. } => name,
+            DNSRecord::NS { ref name, .. } => name,
+            DNSRecord::CDS { ref name, .. } => name,
+            DNSRecord::CDNSKEY { ref name, .. } => name,
+            DNSRecord::OPT { ref name, .. } => name,
+            DNSRecord::CERT { ref name, .. } => name,
+            DNSRecord::NSEC3 { ref name, .. } => name,
+            DNSRecord::EUI48 { ref name, .. } => name,
+            DNSRecord::EUI64 { ref name, .. } => name,
+            DNSRecord::WKS { ref name, .. } => name,
+            DNSRecord::TXT { ref name, .. } => name,
+            DNSRecord::PTR { ref name, .. } => name,
+            DNSRecord::CSYNC { ref name, .. } => name,
+            DNSRecord::ZONEMD { ref name, .. } => name,
+            DNSRecord::CAA { ref name, .. } => name,
+            DNSRecord::UNKNOWN { ref name, .. } => name,
+        }
+    }
+
+    pub fn get_type(&self) -> QueryType {
+        match *self {
+            DNSRecord::A { q_type, .. } => q_type,
+            DNSRecord::AAAA { q_type, .. } => q_type,
+            DNSRecord::CNAME { q_type, .. } => q_type,
+            DNSRecord::SOA { q_type, .. } => q_type,
+            DNSRecord::MX { q_type, .. } => q_type,
+            DNSRecord::NS { q_type, .. } => q_type,
+            DNSRecord::CDS { q_type, .. } => q_type,
+            DNSRecord::CDNSKEY { q_type, .. } => q_type,
+            DNSRecord::OPT { q_type, .. } => q_type,
+            DNSRecord::CERT { q_type, .. } => q_type,
+            DNSRecord::NSEC3 { q_type, .. } => q_type,
+            DNSRecord::EUI48 { q_type, .. } => q_type,
+            DNSRecord::EUI64 { q_type, .. } => q_type,
+            DNSRecord::WKS { q_type, .. } => q_type,
+            DNSRecord::TXT { q_type, .. } => q_type,
+            DNSRecord::PTR { q_type, .. } => q_type,
+            DNSRecord::CSYNC { q_type, .. } => q_type,
+            DNSRecord::ZONEMD { q_type, .. } => q_type,
+            DNSRecord::CAA { q_type, .. } => q_type,
+            DNSRecord::UNKNOWN { q_type, .. } => q_type,
+        }
+    }
+
+    pub fn get_class(&self) -> u16 {
+        match *self {
+            DNSRecord::A { class, .. } => class,
+            DNSRecord::AAAA { class, .. } => class,
+            DNSRecord::CNAME { class, .. } => class,
+            DNSRecord::SOA { class, .. } => class,
+            DNSRecord::MX { class, .. } => class,
+            DNSRecord::NS { class, .. } => class,
+            DNSRecord::CDS { class, .. } => class,
+            DNSRecord::CDNSKEY { class, .. } => class,
+            DNSRecord::OPT { class, .. } => class,
+            DNSRecord::CERT { class, .. } => class,
+            DNSRecord::NSEC3 { class, .. } => class,
+            DNSRecord::EUI48 { class, .. } => class,
+            DNSRecord::EUI64 { class, .. } => class,
+            DNSRecord::WKS { class, .. } => class,
+            DNSRecord::TXT { class, .. } => class,
+            DNSRecord::PTR { class, .. } => class,
+            DNSRecord::CSYNC { class, .. } => class,
+            DNSRecord::ZONEMD { class, .. } => class,
+            DNSRecord::CAA { class, .. } => class,
+            DNSRecord::UNKNOWN { class, .. } => class,
+        }
+    }
+
+    // Data of the first EDNS option with `code`, for OPT records only
+    pub fn opt_option(&self, code: u16) -> Option<&[u8]> {
+        match self {
+            DNSRecord::OPT { options, .. } => options
+                .iter()
+                .find(|(option_code, _)| *option_code == code)
+                .map(|(_, data)| data.as_slice()),
+            _ => None,
+        }
+    }
+
+    pub fn set_ttl(&mut self, new_ttl: u32) {
+        match *self {
+            DNSRecord::A { ref mut ttl, .. } => *ttl = new_ttl,
+            DNSRecord::AAAA { ref mut ttl, .. } => *ttl = new_ttl,
+            DNSRecord::CNAME { ref mut ttl, .. } => *ttl = new_ttl,
+            DNSRecord::SOA { ref mut ttl, .. } => *ttl = new_ttl,
+            DNSRecord::MX { ref mut ttl, .. } => *ttl = new_ttl,
+            DNSRecord::NS { ref mut ttl, .. } => *ttl = new_ttl,
+            DNSRecord::CDS { ref mut ttl, .. } => *ttl = new_ttl,
+            DNSRecord::CDNSKEY { ref mut ttl, .. } => *ttl = new_ttl,
+            DNSRecord::OPT { ref mut ttl, .. } => *ttl = new_ttl,
+            DNSRecord::CERT { ref mut ttl, .. } => *ttl = new_ttl,
+            DNSRecord::NSEC3 { ref mut ttl, .. } => *ttl = new_ttl,
+            DNSRecord::EUI48 { ref mut ttl, .. } => *ttl = new_ttl,
+            DNSRecord::EUI64 { ref mut ttl, .. } => *ttl = new_ttl,
+            DNSRecord::WKS { ref mut ttl, .. } => *ttl = new_ttl,
+            DNSRecord::TXT { ref mut ttl, .. } => *ttl = new_ttl,
+            DNSRecord::PTR { ref mut ttl, .. } => *ttl = new_ttl,
+            DNSRecord::CSYNC { ref mut ttl, .. } => *ttl = new_ttl,
+            DNSRecord::ZONEMD { ref mut ttl, .. } => *ttl = new_ttl,
+            DNSRecord::CAA { ref mut ttl, .. } => *ttl = new_ttl,
+            DNSRecord::UNKNOWN { ref mut ttl, .. } => *ttl = new_ttl,
+        }
+    }
+
+    // Compares everything after the record header, `len` excepted
+    fn rdata_eq(&self, other: &DNSRecord) -> bool {
+        match (self, other) {
+            (DNSRecord::UNKNOWN { data: a, .. }, DNSRecord::UNKNOWN { data: b, .. }) => a == b,
+            (DNSRecord::A { addr: a, .. }, DNSRecord::A { addr: b, .. }) => a == b,
+            (DNSRecord::NS { host: a, .. }, DNSRecord::NS { host: b, .. }) => a == b,
+            (DNSRecord::CNAME { host: a, .. }, DNSRecord::CNAME { host: b, .. }) => a == b,
+            (
+                DNSRecord::MX {
+                    priority: priority_a,
+                    host: host_a,
+                    ..
+                },
+                DNSRecord::MX {
+                    priority: priority_b,
+                    host: host_b,
+                    ..
+                },
+            ) => priority_a == priority_b && host_a == host_b,
+            (DNSRecord::AAAA { addr: a, .. }, DNSRecord::AAAA { addr: b, .. }) => a == b,
+            (
+                DNSRecord::SOA {
+                    mname: mname_a,
+                    rname: rname_a,
+                    serial: serial_a,
+                    refresh: refresh_a,
+                    retry: retry_a,
+                    expire: expire_a,
+                    minimum: minimum_a,
+                    ..
+                },
+                DNSRecord::SOA {
+                    mname: mname_b,
+                    rname: rname_b,
+                    serial: serial_b,
+                    refresh: refresh_b,
+                    retry: retry_b,
+                    expire: expire_b,
+                    minimum: minimum_b,
+                    ..
+                },
+            ) => {
+                mname_a == mname_b
+                    && rname_a == rname_b
+                    && serial_a == serial_b
+                    && refresh_a == refresh_b
+                    && retry_a == retry_b
+                    && expire_a == expire_b
+                    && minimum_a == minimum_b
+            }
+            (
+                DNSRecord::CDS {
+                    key_tag: key_tag_a,
+                    algorithm: algorithm_a,
+                    digest_type: digest_type_a,
+                    digest: digest_a,
+                    ..
+                },
+                DNSRecord::CDS {
+                    key_tag: key_tag_b,
+                    algorithm: algorithm_b,
+                    digest_type: digest_type_b,
+                    digest: digest_b,
+                    ..
+                },
+            ) => {
+                key_tag_a == key_tag_b
+                    && algorithm_a == algorithm_b
+                    && digest_type_a == digest_type_b
+                    && digest_a == digest_b
+            }
+            (
+                DNSRecord::CDNSKEY {
+                    flags: flags_a,
+                    protocol: protocol_a,
+                    algorithm: algorithm_a,
+                    public_key: public_key_a,
+                    ..
+                },
+                DNSRecord::CDNSKEY {
+                    flags: flags_b,
+                    protocol: protocol_b,
+                    algorithm: algorithm_b,
+                    public_key: public_key_b,
+                    ..
+                },
+            ) => {
+                flags_a == flags_b
+                    && protocol_a == protocol_b
+                    && algorithm_a == algorithm_b
+                    && public_key_a == public_key_b
+            }
+            (DNSRecord::OPT { options: a, .. }, DNSRecord::OPT { options: b, .. }) => a == b,
+            (
+                DNSRecord::CERT {
+                    cert_type: cert_type_a,
+                    key_tag: key_tag_a,
+                    algorithm: algorithm_a,
+                    certificate: certificate_a,
+                    ..
+                },
+                DNSRecord::CERT {
+                    cert_type: cert_type_b,
+                    key_tag: key_tag_b,
+                    algorithm: algorithm_b,
+                    certificate: certificate_b,
+                    ..
+                },
+            ) => {
+                cert_type_a == cert_type_b
+                    && key_tag_a == key_tag_b
+                    && algorithm_a == algorithm_b
+                    && certificate_a == certificate_b
+            }
+            (
+                DNSRecord::NSEC3 {
+                    hash_algorithm: hash_algorithm_a,
+                    flags: flags_a,
+                    iterations: iterations_a,
+                    salt: salt_a,
+                    next_hashed: next_hashed_a,
+                    type_bitmaps: type_bitmaps_a,
+                    ..
+                },
+                DNSRecord::NSEC3 {
+                    hash_algorithm: hash_algorithm_b,
+                    flags: flags_b,
+                    iterations: iterations_b,
+                    salt: salt_b,
+                    next_hashed: next_hashed_b,
+                    type_bitmaps: type_bitmaps_b,
+                    ..
+                },
+            ) => {
+                hash_algorithm_a == hash_algorithm_b
+                    && flags_a == flags_b
+                    && iterations_a == iterations_b
+                    && salt_a == salt_b
+                    && next_hashed_a == next_hashed_b
+                    && type_bitmaps_a == type_bitmaps_b
+            }
+            (DNSRecord::EUI48 { address: a, .. }, DNSRecord::EUI48 { address: b, .. }) => a == b,
+            (DNSRecord::EUI64 { address: a, .. }, DNSRecord::EUI64 { address: b, .. }) => a == b,
+            (
+                DNSRecord::WKS {
+                    addr: addr_a,
+                    protocol: protocol_a,
+                    bitmap: bitmap_a,
+                    ..
+                },
+                DNSRecord::WKS {
+                    addr: addr_b,
+                    protocol: protocol_b,
+                    bitmap: bitmap_b,
+                    ..
+                },
+            ) => addr_a == addr_b && protocol_a == protocol_b && bitmap_a == bitmap_b,
+            (DNSRecord::TXT { data: a, .. }, DNSRecord::TXT { data: b, .. }) => a == b,
+            (DNSRecord::PTR { host: a, .. }, DNSRecord::PTR { host: b, .. }) => a == b,
+            (
+                DNSRecord::CSYNC {
+                    soa_serial: soa_serial_a,
+                    flags: flags_a,
+                    type_bitmap: type_bitmap_a,
+                    ..
+                },
+                DNSRecord::CSYNC {
+                    soa_serial: soa_serial_b,
+                    flags: flags_b,
+                    type_bitmap: type_bitmap_b,
+                    ..
+                },
+            ) => {
+                soa_serial_a == soa_serial_b && flags_a == flags_b && type_bitmap_a == type_bitmap_b
+            }
+            (
+                DNSRecord::ZONEMD {
+                    serial: serial_a,
+                    scheme: scheme_a,
+                    hash_algorithm: hash_algorithm_a,
+                    digest: digest_a,
+                    ..
+                },
+                DNSRecord::ZONEMD {
+                    serial: serial_b,
+                    scheme: scheme_b,
+                    hash_algorithm: hash_algorithm_b,
+                    digest: digest_b,
+                    ..
+                },
+            ) => {
+                serial_a == serial_b
+                    && scheme_a == scheme_b
+                    && hash_algorithm_a == hash_algorithm_b
+                    && digest_a == digest_b
+            }
+            (
+                DNSRecord::CAA {
+                    flags: flags_a,
+                    tag: tag_a,
+                    value: value_a,
+                    ..
+                },
+                DNSRecord::CAA {
+                    flags: flags_b,
+                    tag: tag_b,
+                    value: value_b,
+                    ..
+                },
+            ) => flags_a == flags_b && tag_a == tag_b && value_a == value_b,
+            _ => false,
+        }
+    }
+
+    // Reads a record, recovering from bad record data through its RDLENGTH.
+    // None for a skipped record, an error when even the record header is cut off.
+    fn read_lenient<T: PacketBufferTrait>(
+        buf: &mut T,
+        section: Section,
+        warnings: &mut Vec<ParseWarning>,
+    ) -> Result<Option<DNSRecord>> {
+        let start = buf.pos();
+        let mut domain = String::new();
+        buf.read_qname(&mut domain)?;
+        let q_type = QueryType::from_num(buf.read_u16()?);
+        buf.step(6)?; // Class and TTL
+        let len = buf.read_u16()? as usize;
+        let end = buf.pos() + len;
+        buf.seek(start)?;
+        let record = match DNSRecord::read(buf) {
+            Ok(record) => record,
+            Err(err) => {
+                warnings.push(ParseWarning::MalformedRecord(section, err.to_string()));
+                buf.seek(end)?;
+                return Ok(None);
+            }
+        };
+        if let QueryType::UNKNOWN(code) = q_type {
+            warnings.push(ParseWarning::UnknownType(section, code));
+        }
+        if buf.pos() != end {
+            warnings.push(ParseWarning::LengthMismatch(section, q_type));
+            buf.seek(end)?;
+        }
+        Ok(Some(record))
+    }
+
     pub fn read<T: PacketBufferTrait>(buf: &mut T) -> Result<DNSRecord> {
         let mut domain = String::new();
         buf.read_qname(&mut domain)?;
@@ -262,11 +1126,11 @@ impl DNSRecord {
                 );
                 Ok(DNSRecord::A {
                     name: domain,
-                    q_type: q_type,
-                    class: class,
-                    ttl: ttl,
-                    len: len,
-                    addr: addr,
+                    q_type,
+                    class,
+                    ttl,
+                    len,
+                    addr,
                 })
             }
             QueryType::NS => {
@@ -274,11 +1138,11 @@ impl DNSRecord {
                 buf.read_qname(&mut host)?;
                 Ok(DNSRecord::NS {
                     name: domain,
-                    q_type: q_type,
-                    class: class,
-                    ttl: ttl,
-                    len: len,
-                    host: host,
+                    q_type,
+                    class,
+                    ttl,
+                    len,
+                    host,
                 })
             }
             QueryType::CNAME => {
@@ -286,11 +1150,11 @@ impl DNSRecord {
                 buf.read_qname(&mut host)?;
                 Ok(DNSRecord::CNAME {
                     name: domain,
-                    q_type: q_type,
-                    class: class,
-                    ttl: ttl,
-                    len: len,
-                    host: host,
+                    q_type,
+                    class,
+                    ttl,
+                    len,
+                    host,
                 })
             }
             QueryType::MX => {
@@ -299,12 +1163,12 @@ impl DNSRecord {
                 buf.read_qname(&mut host)?;
                 Ok(DNSRecord::MX {
                     name: domain,
-                    q_type: q_type,
-                    class: class,
-                    ttl: ttl,
-                    len: len,
-                    priority: priority,
-                    host: host,
+                    q_type,
+                    class,
+                    ttl,
+                    len,
+                    priority,
+                    host,
                 })
             }
             QueryType::AAAA => {
@@ -325,11 +1189,11 @@ impl DNSRecord {
                 );
                 Ok(DNSRecord::AAAA {
                     name: domain,
-                    q_type: q_type,
-                    class: class,
-                    ttl: ttl,
-                    len: len,
-                    addr: addr,
+                    q_type,
+                    class,
+                    ttl,
+                    len,
+                    addr,
                 })
             }
             QueryType::SOA => {
@@ -345,10 +1209,10 @@ impl DNSRecord {
 
                 Ok(DNSRecord::SOA {
                     name: domain,
-                    q_type: q_type,
-                    class: class,
-                    ttl: ttl,
-                    len: len,
+                    q_type,
+                    class,
+                    ttl,
+                    len,
                     mname,
                     rname,
                     serial,
@@ -358,108 +1222,297 @@ impl DNSRecord {
                     minimum,
                 })
             }
-            QueryType::UNKNOWN(_) => {
-                buf.step(len as usize)?; // Skip the data length of this particular record type
+            QueryType::CDS => {
+                // Same RDATA layout as DS (RFC 4034 section 5.1)
+                let digest_len = variable_rdata_len(q_type, len, 4)?;
+                let key_tag = buf.read_u16()?;
+                let algorithm = buf.read()?;
+                let digest_type = buf.read()?;
+                let digest = buf.read_bytes(digest_len)?;
+                Ok(DNSRecord::CDS {
+                    name: domain,
+                    q_type,
+                    class,
+                    ttl,
+                    len,
+                    key_tag,
+                    algorithm,
+                    digest_type,
+                    digest,
+                })
+            }
+            QueryType::CDNSKEY => {
+                // Same RDATA layout as DNSKEY (RFC 4034 section 2.1)
+                let key_len = variable_rdata_len(q_type, len, 4)?;
+                let flags = buf.read_u16()?;
+                let protocol = buf.read()?;
+                let algorithm = buf.read()?;
+                let public_key = buf.read_bytes(key_len)?;
+                Ok(DNSRecord::CDNSKEY {
+                    name: domain,
+                    q_type,
+                    class,
+                    ttl,
+                    len,
+                    flags,
+                    protocol,
+                    algorithm,
+                    public_key,
+                })
+            }
+            QueryType::OPT => {
+                // EDNS pseudo-record (RFC 6891): class carries the UDP payload size and ttl the
+                // extended rcode, version and flags. RDATA is a list of (code, data) options.
+                let mut options = Vec::new();
+                let end = buf.pos() + len as usize;
+                while buf.pos() < end {
+                    if buf.pos() + 4 > end {
+                        return Err(eyre!("EDNS option header overruns its OPT RDATA"));
+                    }
+                    let code = buf.read_u16()?;
+                    let option_len = buf.read_u16()? as usize;
+                    if buf.pos() + option_len > end {
+                        return Err(eyre!(
+                            "EDNS option {} of length {} overruns its OPT RDATA",
+                            code,
+                            option_len
+                        ));
+                    }
+                    options.push((code, buf.read_bytes(option_len)?));
+                }
+                Ok(DNSRecord::OPT {
+                    name: domain,
+                    q_type,
+                    class,
+                    ttl,
+                    len,
+                    options,
+                })
+            }
+            QueryType::CERT => {
+                let certificate_len = variable_rdata_len(q_type, len, 5)?;
+                let cert_type = buf.read_u16()?;
+                let key_tag = buf.read_u16()?;
+                let algorithm = buf.read()?;
+                let certificate = buf.read_bytes(certificate_len)?;
+                Ok(DNSRecord::CERT {
+                    name: domain,
+                    q_type,
+                    class,
+                    ttl,
+                    len,
+                    cert_type,
+                    key_tag,
+                    algorithm,
+                    certificate,
+                })
+            }
+            QueryType::NSEC3 => {
+                // RFC 5155 section 3.2
+                let start = buf.pos();
+                let hash_algorithm = buf.read()?;
+                let flags = buf.read()?;
+                let iterations = buf.read_u16()?;
+                let salt_len = buf.read()?;
+                let salt = buf.read_bytes(salt_len as usize)?;
+                let hash_len = buf.read()?;
+                let next_hashed = buf.read_bytes(hash_len as usize)?;
+                let bitmaps_len = variable_rdata_len(q_type, len, buf.pos() - start)?;
+                let type_bitmaps = buf.read_bytes(bitmaps_len)?;
+                Ok(DNSRecord::NSEC3 {
+                    name: domain,
+                    q_type,
+                    class,
+                    ttl,
+                    len,
+                    hash_algorithm,
+                    flags,
+                    iterations,
+                    salt,
+                    next_hashed,
+                    type_bitmaps,
+                })
+            }
+            QueryType::EUI48 => {
+                // RFC 7043 section 3
+                if len != 6 {
+                    return Err(eyre!("EUI48 RDATA must be 6 bytes, not {}", len));
+                }
+                let mut address = [0; 6];
+                address.copy_from_slice(&buf.read_bytes(6)?);
+                Ok(DNSRecord::EUI48 {
+                    name: domain,
+                    q_type,
+                    class,
+                    ttl,
+                    len,
+                    address,
+                })
+            }
+            QueryType::EUI64 => {
+                // RFC 7043 section 4
+                if len != 8 {
+                    return Err(eyre!("EUI64 RDATA must be 8 bytes, not {}", len));
+                }
+                let mut address = [0; 8];
+                address.copy_from_slice(&buf.read_bytes(8)?);
+                Ok(DNSRecord::EUI64 {
+                    name: domain,
+                    q_type,
+                    class,
+                    ttl,
+                    len,
+                    address,
+                })
+            }
+            QueryType::WKS => {
+                // RFC 1035 section 3.4.2
+                let bitmap_len = variable_rdata_len(q_type, len, 5)?;
+                let addr = Ipv4Addr::from(buf.read_u32()?);
+                let protocol = buf.read()?;
+                let bitmap = buf.read_bytes(bitmap_len)?;
+                Ok(DNSRecord::WKS {
+                    name: domain,
+                    q_type,
+                    class,
+                    ttl,
+                    len,
+                    addr,
+                    protocol,
+                    bitmap,
+                })
+            }
+            QueryType::TXT => {
+                let data = buf.read_character_strings(len as usize)?;
+                Ok(DNSRecord::TXT {
+                    name: domain,
+                    q_type,
+                    class,
+                    ttl,
+                    len,
+                    data,
+                })
+            }
+            QueryType::PTR => {
+                let mut host = String::new();
+                buf.read_qname(&mut host)?;
+                Ok(DNSRecord::PTR {
+                    name: domain,
+                    q_type,
+                    class,
+                    ttl,
+                    len,
+                    host,
+                })
+            }
+            QueryType::CSYNC => {
+                // RFC 7477 section 2.1, the type bitmap is encoded as in NSEC3 records
+                let bitmap_len = variable_rdata_len(q_type, len, 6)?;
+                let soa_serial = buf.read_u32()?;
+                let flags = buf.read_u16()?;
+                let type_bitmap = buf.read_bytes(bitmap_len)?;
+                Ok(DNSRecord::CSYNC {
+                    name: domain,
+                    q_type,
+                    class,
+                    ttl,
+                    len,
+                    soa_serial,
+                    flags,
+                    type_bitmap,
+                })
+            }
+            QueryType::ZONEMD => {
+                // RFC 8976 section 2.2
+                let digest_len = variable_rdata_len(q_type, len, 6)?;
+                let serial = buf.read_u32()?;
+                let scheme = buf.read()?;
+                let hash_algorithm = buf.read()?;
+                let digest = buf.read_bytes(digest_len)?;
+                Ok(DNSRecord::ZONEMD {
+                    name: domain,
+                    q_type,
+                    class,
+                    ttl,
+                    len,
+                    serial,
+                    scheme,
+                    hash_algorithm,
+                    digest,
+                })
+            }
+            QueryType::CAA => {
+                // RFC 8659 section 4.1, the value runs to the end of the RDATA
+                let flags = buf.read()?;
+                let tag_len = buf.read()? as usize;
+                if 2 + tag_len > len as usize {
+                    return Err(eyre!(
+                        "CAA tag of length {} overruns its {} byte RDATA",
+                        tag_len,
+                        len
+                    ));
+                }
+                let tag = String::from_utf8_lossy(&buf.read_bytes(tag_len)?).into_owned();
+                let value = String::from_utf8_lossy(&buf.read_bytes(len as usize - 2 - tag_len)?)
+                    .into_owned();
+                Ok(DNSRecord::CAA {
+                    name: domain,
+                    q_type,
+                    class,
+                    ttl,
+                    len,
+                    flags,
+                    tag,
+                    value,
+                })
+            }
+            QueryType::UNKNOWN(_) | QueryType::ANY => {
+                let data = buf.read_bytes(len as usize)?;
                 Ok(DNSRecord::UNKNOWN {
                     name: domain,
-                    q_type: q_type,
-                    class: class,
-                    ttl: ttl,
-                    len: len,
+                    q_type,
+                    class,
+                    ttl,
+                    len,
+                    data,
                 })
             }
         }
     }
 
     pub fn write<T: PacketBufferTrait>(&self, buf: &mut T) -> Result<()> {
+        buf.write_qname(self.get_name())?;
+        buf.write_u16(self.get_type().to_num())?;
+        buf.write_u16(self.get_class())?;
+        buf.write_u32(self.get_ttl())?;
+        // RDLENGTH is patched in once the data is written, since names inside it
+        // may be compressed differently than when the record was parsed
+        let len_pos = buf.pos();
+        buf.write_u16(0)?;
         match *self {
-            DNSRecord::A {
-                ref name,
-                q_type,
-                class,
-                ttl,
-                len,
-                ref addr,
-            } => {
-                buf.write_qname(&name)?;
-                buf.write_u16(q_type.to_num())?;
-                buf.write_u16(class)?;
-                buf.write_u32(ttl)?;
-                buf.write_u16(len)?;
+            DNSRecord::A { ref addr, .. } => {
                 for octet in addr.octets().iter() {
                     buf.write(*octet)?;
                 }
             }
-            DNSRecord::NS {
-                ref name,
-                q_type,
-                class,
-                ttl,
-                len,
-                ref host,
-            } => {
-                buf.write_qname(&name)?;
-                buf.write_u16(q_type.to_num())?;
-                buf.write_u16(class)?;
-                buf.write_u32(ttl)?;
-                buf.write_u16(len)?;
+            DNSRecord::NS { ref host, .. } => {
                 buf.write_qname(host)?;
             }
-            DNSRecord::CNAME {
-                ref name,
-                q_type,
-                class,
-                ttl,
-                len,
-                ref host,
-            } => {
-                buf.write_qname(&name)?;
-                buf.write_u16(q_type.to_num())?;
-                buf.write_u16(class)?;
-                buf.write_u32(ttl)?;
-                buf.write_u16(len)?;
+            DNSRecord::CNAME { ref host, .. } => {
                 buf.write_qname(host)?;
             }
             DNSRecord::MX {
-                ref name,
-                q_type,
-                class,
-                ttl,
-                len,
-                priority,
-                ref host,
+                priority, ref host, ..
             } => {
-                buf.write_qname(&name)?;
-                buf.write_u16(q_type.to_num())?;
-                buf.write_u16(class)?;
-                buf.write_u32(ttl)?;
-                buf.write_u16(len)?;
                 buf.write_u16(priority)?;
                 buf.write_qname(host)?;
             }
-            DNSRecord::AAAA {
-                ref name,
-                q_type,
-                class,
-                ttl,
-                len,
-                ref addr,
-            } => {
-                buf.write_qname(&name)?;
-                buf.write_u16(q_type.to_num())?;
-                buf.write_u16(class)?;
-                buf.write_u32(ttl)?;
-                buf.write_u16(len)?;
+            DNSRecord::AAAA { ref addr, .. } => {
                 for segment in addr.segments().iter() {
                     buf.write_u16(*segment)?;
                 }
             }
             DNSRecord::SOA {
-                ref name,
-                q_type,
-                class,
-                ttl,
-                len,
                 ref mname,
                 ref rname,
                 serial,
@@ -467,35 +1520,225 @@ impl DNSRecord {
                 retry,
                 expire,
                 minimum,
+                ..
             } => {
-                buf.write_qname(&name)?;
-                buf.write_u16(q_type.to_num())?;
-                buf.write_u16(class)?;
-                buf.write_u32(ttl)?;
-                buf.write_u16(len)?;
-                buf.write_qname(&mname)?;
-                buf.write_qname(&rname)?;
+                buf.write_qname(mname)?;
+                buf.write_qname(rname)?;
                 buf.write_u32(serial)?;
                 buf.write_u32(refresh)?;
                 buf.write_u32(retry)?;
                 buf.write_u32(expire)?;
                 buf.write_u32(minimum)?;
             }
-            DNSRecord::UNKNOWN { .. } => {
-                println!("SKipping unknown record!");
+            DNSRecord::CDS {
+                key_tag,
+                algorithm,
+                digest_type,
+                ref digest,
+                ..
+            } => {
+                buf.write_u16(key_tag)?;
+                buf.write(algorithm)?;
+                buf.write(digest_type)?;
+                buf.write_bytes(digest)?;
             }
-        }
-        Ok(())
-    }
-}
-
-#[derive(Debug, Clone)]
-pub struct DNSPacket {
-    pub header: DNSHeader,
-    pub questions: Vec<DNSQuestion>,
-    pub answers: Vec<DNSRecord>,
+            DNSRecord::CDNSKEY {
+                flags,
+                protocol,
+                algorithm,
+                ref public_key,
+                ..
+            } => {
+                buf.write_u16(flags)?;
+                buf.write(protocol)?;
+                buf.write(algorithm)?;
+                buf.write_bytes(public_key)?;
+            }
+            DNSRecord::OPT { ref options, .. } => {
+                for (code, data) in options {
+                    buf.write_u16(*code)?;
+                    buf.write_u16(data.len() as u16)?;
+                    buf.write_bytes(data)?;
+                }
+            }
+            DNSRecord::CERT {
+                cert_type,
+                key_tag,
+                algorithm,
+                ref certificate,
+                ..
+            } => {
+                buf.write_u16(cert_type)?;
+                buf.write_u16(key_tag)?;
+                buf.write(algorithm)?;
+                buf.write_bytes(certificate)?;
+            }
+            DNSRecord::NSEC3 {
+                hash_algorithm,
+                flags,
+                iterations,
+                ref salt,
+                ref next_hashed,
+                ref type_bitmaps,
+                ..
+            } => {
+                buf.write(hash_algorithm)?;
+                buf.write(flags)?;
+                buf.write_u16(iterations)?;
+                buf.write(salt.len() as u8)?;
+                buf.write_bytes(salt)?;
+                buf.write(next_hashed.len() as u8)?;
+                buf.write_bytes(next_hashed)?;
+                buf.write_bytes(type_bitmaps)?;
+            }
+            DNSRecord::EUI48 { ref address, .. } => {
+                buf.write_bytes(address)?;
+            }
+            DNSRecord::EUI64 { ref address, .. } => {
+                buf.write_bytes(address)?;
+            }
+            DNSRecord::WKS {
+                addr,
+                protocol,
+                ref bitmap,
+                ..
+            } => {
+                buf.write_u32(u32::from(addr))?;
+                buf.write(protocol)?;
+                buf.write_bytes(bitmap)?;
+            }
+            DNSRecord::TXT { ref data, .. } => {
+                for text in data {
+                    if text.len() > 255 {
+                        return Err(eyre!("TXT string exceeds 255 bytes: {}", text.len()));
+                    }
+                    buf.write(text.len() as u8)?;
+                    buf.write_bytes(text.as_bytes())?;
+                }
+            }
+            DNSRecord::PTR { ref host, .. } => {
+                buf.write_qname(host)?;
+            }
+            DNSRecord::CSYNC {
+                soa_serial,
+                flags,
+                ref type_bitmap,
+                ..
+            } => {
+                buf.write_u32(soa_serial)?;
+                buf.write_u16(flags)?;
+                buf.write_bytes(type_bitmap)?;
+            }
+            DNSRecord::ZONEMD {
+                serial,
+                scheme,
+                hash_algorithm,
+                ref digest,
+                ..
+            } => {
+                buf.write_u32(serial)?;
+                buf.write(scheme)?;
+                buf.write(hash_algorithm)?;
+                buf.write_bytes(digest)?;
+            }
+            DNSRecord::CAA {
+                flags,
+                ref tag,
+                ref value,
+                ..
+            } => {
+                if tag.is_empty() || tag.len() > 255 {
+                    return Err(eyre!("CAA tag must be 1 to 255 bytes long"));
+                }
+                buf.write(flags)?;
+                buf.write(tag.len() as u8)?;
+                buf.write_bytes(tag.as_bytes())?;
+                buf.write_bytes(value.as_bytes())?;
+            }
+            DNSRecord::UNKNOWN { ref data, .. } => {
+                buf.write_bytes(data)?;
+            }
+        }
+        let rdata_len = buf.pos() - len_pos - 2;
+        buf.set(len_pos, (rdata_len >> 8) as u8)?;
+        buf.set(len_pos + 1, (rdata_len & 0xFF) as u8)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FormErrReason {
+    NoQuestion,
+    MultipleQuestions,
+    NotAQuery,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RequestKind {
+    Standard,
+    Notify,
+    Update,
+    Unsupported(OpCode),
+    Malformed(FormErrReason),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Section {
+    Answer,
+    Authority,
+    Additional,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PacketDiff {
+    RCode { ours: RCode, theirs: RCode },
+    // Record only present in our packet
+    Missing(Section, DNSRecord),
+    // Record only present in the other packet
+    Extra(Section, DNSRecord),
+}
+
+// Recoverable problems found by `DNSPacket::from_buffer_lenient`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseWarning {
+    // Record of a type this parser doesn't know, kept as an UNKNOWN record
+    UnknownType(Section, u16),
+    // Record whose data didn't parse, skipped using its RDLENGTH
+    MalformedRecord(Section, String),
+    // Record whose data didn't take up exactly its RDLENGTH
+    LengthMismatch(Section, QueryType),
+    // The packet ended before every record the header announced
+    Truncated(Section),
+}
+
+// What a response is allowed to carry, see `DNSPacket::sanitize`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ResponseContext {
+    // The client sent an OPT record, so the response carries the server's own
+    pub edns: bool,
+    // The answer came from data the server is authoritative for
+    pub authoritative: bool,
+    pub recursion_available: bool,
+    // DO and CD as the client sent them, both are echoed back
+    pub dnssec_ok: bool,
+    pub checking_disabled: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct DNSPacket {
+    pub header: DNSHeader,
+    pub questions: Vec<DNSQuestion>,
+    pub answers: Vec<DNSRecord>,
     pub authority: Vec<DNSRecord>,
     pub addtional: Vec<DNSRecord>,
+    // Extended DNS Error (info code, extra text), moved into the OPT record on write
+    pub ede: Option<(u16, String)>,
+}
+
+impl Default for DNSPacket {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl DNSPacket {
@@ -506,11 +1749,37 @@ impl DNSPacket {
             answers: Vec::new(),
             authority: Vec::new(),
             addtional: Vec::new(),
+            ede: None,
         }
     }
     pub fn from_buffer<T: PacketBufferTrait>(buf: &mut T) -> Result<DNSPacket> {
+        DNSPacket::from_buffer_with_limit(buf, DEFAULT_MAX_RECORDS)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<DNSPacket> {
+        let mut buf = VecBuffer::new();
+        buf.buf = bytes.to_vec();
+        DNSPacket::from_buffer(&mut buf)
+    }
+
+    // Rejects the packet before parsing any record if its header claims more
+    // than `max_records` resource records across all sections
+    pub fn from_buffer_with_limit<T: PacketBufferTrait>(
+        buf: &mut T,
+        max_records: usize,
+    ) -> Result<DNSPacket> {
         let mut result = DNSPacket::new();
         result.header.read(buf)?;
+        let record_count = result.header.an_count as usize
+            + result.header.ns_count as usize
+            + result.header.ad_count as usize;
+        if record_count > max_records {
+            return Err(eyre!(
+                "Packet declares {} records, more than the limit of {}",
+                record_count,
+                max_records
+            ));
+        }
         for _ in 0..result.header.q_count {
             let question = DNSQuestion::read(buf)?;
             result.questions.push(question);
@@ -528,18 +1797,189 @@ impl DNSPacket {
         for _ in 0..result.header.ad_count {
             result.addtional.push(DNSRecord::read(buf)?);
         }
+        result.read_extended_rcode();
         Ok(result)
     }
 
+    // Parses as much of a broken packet as possible for diagnostics, skipping
+    // records that don't parse instead of failing. Only the header and the
+    // questions have to be intact.
+    pub fn from_buffer_lenient<T: PacketBufferTrait>(
+        buf: &mut T,
+    ) -> Result<(DNSPacket, Vec<ParseWarning>)> {
+        let mut result = DNSPacket::new();
+        let mut warnings = Vec::new();
+        result.header.read(buf)?;
+        for _ in 0..result.header.q_count {
+            let question = DNSQuestion::read(buf)?;
+            result.questions.push(question);
+        }
+        let sections = [
+            (Section::Answer, result.header.an_count),
+            (Section::Authority, result.header.ns_count),
+            (Section::Additional, result.header.ad_count),
+        ];
+        for (section, count) in sections.iter() {
+            for _ in 0..*count {
+                let record = match DNSRecord::read_lenient(buf, *section, &mut warnings) {
+                    Ok(Some(record)) => record,
+                    Ok(None) => continue,
+                    Err(_) => {
+                        warnings.push(ParseWarning::Truncated(*section));
+                        result.read_extended_rcode();
+                        return Ok((result, warnings));
+                    }
+                };
+                match section {
+                    Section::Answer => result.answers.push(record),
+                    Section::Authority => result.authority.push(record),
+                    Section::Additional => result.addtional.push(record),
+                }
+            }
+        }
+        result.read_extended_rcode();
+        Ok((result, warnings))
+    }
+
+    // Decides up front how the server should treat an incoming request, so
+    // the FORMERR/NOTIMP cases are handled before any resolution happens.
+    pub fn classify_request(&self) -> RequestKind {
+        if self.header.query_response {
+            return RequestKind::Malformed(FormErrReason::NotAQuery);
+        }
+        match self.header.opcode {
+            OpCode::QUERY => {}
+            OpCode::NOTIFY => return RequestKind::Notify,
+            OpCode::UPDATE => return RequestKind::Update,
+            opcode => return RequestKind::Unsupported(opcode),
+        }
+        match self.questions.len() {
+            0 => RequestKind::Malformed(FormErrReason::NoQuestion),
+            1 => RequestKind::Standard,
+            _ => RequestKind::Malformed(FormErrReason::MultipleQuestions),
+        }
+    }
+
     pub fn add_question(&mut self, question: DNSQuestion) {
         self.questions.push(question);
         self.header.q_count += 1;
     }
 
+    // Replaces the whole question section with `question`
+    pub fn set_question(&mut self, question: DNSQuestion) {
+        self.questions.clear();
+        self.header.q_count = 0;
+        self.add_question(question);
+    }
+
+    pub fn with_question(mut self, question: DNSQuestion) -> Self {
+        self.set_question(question);
+        self
+    }
+
+    pub fn get_opt(&self) -> Option<&DNSRecord> {
+        self.addtional
+            .iter()
+            .find(|record| matches!(record, DNSRecord::OPT { .. }))
+    }
+
+    fn get_or_add_opt(&mut self) -> &mut DNSRecord {
+        if self.get_opt().is_none() {
+            self.addtional.push(DNSRecord::OPT {
+                name: String::new(),
+                q_type: QueryType::OPT,
+                class: 512,
+                ttl: 0,
+                len: 0,
+                options: Vec::new(),
+            });
+        }
+        self.addtional
+            .iter_mut()
+            .find(|record| matches!(record, DNSRecord::OPT { .. }))
+            .unwrap()
+    }
+
+    pub fn add_edns_option(&mut self, code: u16, data: Vec<u8>) {
+        if let DNSRecord::OPT { options, .. } = self.get_or_add_opt() {
+            options.push((code, data));
+        }
+    }
+
+    // Largest UDP response the sender can receive, from the OPT record's class
+    pub fn udp_payload_size(&self) -> Option<u16> {
+        self.get_opt().map(|opt| opt.get_class())
+    }
+
+    pub fn set_udp_payload_size(&mut self, size: u16) {
+        if let DNSRecord::OPT { class, .. } = self.get_or_add_opt() {
+            *class = size;
+        }
+    }
+
+    // Turns the packet into a truncated (TC) response that tells the client to
+    // retry over TCP, keeping only the question and the OPT record
+    pub fn truncate(&mut self) {
+        self.header.truncated_msg = true;
+        self.answers.clear();
+        self.authority.clear();
+        self.addtional
+            .retain(|record| matches!(record, DNSRecord::OPT { .. }));
+    }
+
+    pub fn edns_option(&self, code: u16) -> Option<&[u8]> {
+        self.get_opt()?.opt_option(code)
+    }
+
+    // OPT TTL layout: extended rcode (8 bits), version (8 bits), flags (16 bits)
+    pub fn edns_version(&self) -> Option<u8> {
+        self.get_opt().map(|opt| (opt.get_ttl() >> 16) as u8)
+    }
+
+    pub fn edns_flags(&self) -> Option<u16> {
+        self.get_opt().map(|opt| opt.get_ttl() as u16)
+    }
+
+    pub fn dnssec_ok(&self) -> bool {
+        self.edns_flags().unwrap_or(0) & EDNS_FLAG_DO != 0
+    }
+
+    pub fn set_edns(&mut self, version: u8, flags: u16) {
+        let opt = self.get_or_add_opt();
+        let ttl = (opt.get_ttl() & 0xFF00_0000) | (version as u32) << 16 | flags as u32;
+        opt.set_ttl(ttl);
+    }
+
+    // Splits a 12 bit rcode between the header and the OPT record, which is
+    // added if the packet doesn't carry one yet
+    pub fn set_extended_rcode(&mut self, rcode: u16) {
+        self.header.res_code = RCode::from_num(rcode);
+        let opt = self.get_or_add_opt();
+        let ttl = (opt.get_ttl() & 0x00FF_FFFF) | ((rcode >> 4) as u32) << 24;
+        opt.set_ttl(ttl);
+    }
+
+    // Joins the OPT record's upper rcode bits with the header's lower ones
+    fn read_extended_rcode(&mut self) {
+        if let Some(opt) = self.get_opt() {
+            let upper = (opt.get_ttl() >> 24) as u16;
+            self.header.res_code = RCode::from_num(upper << 4 | self.header.res_code.to_num());
+        }
+    }
+
     pub fn write<T: PacketBufferTrait>(&mut self, buf: &mut T) -> Result<()> {
+        if let Some((code, text)) = self.ede.take() {
+            let mut data = code.to_be_bytes().to_vec();
+            data.extend_from_slice(text.as_bytes());
+            self.add_edns_option(EDNS_OPTION_EDE, data);
+        }
         self.header.q_count = self.questions.len() as u16;
         self.header.an_count = self.answers.len() as u16;
         self.header.ns_count = self.authority.len() as u16;
+        let rcode = self.header.res_code.to_num();
+        if rcode > 0xF || self.get_opt().is_some() {
+            self.set_extended_rcode(rcode);
+        }
         self.header.ad_count = self.addtional.len() as u16;
         self.header.write(buf)?;
         for question in &self.questions {
@@ -557,6 +1997,266 @@ impl DNSPacket {
         Ok(())
     }
 
+    // The packet in wire format, names compressed
+    pub fn to_bytes(&mut self) -> Result<Vec<u8>> {
+        let mut buf = VecBuffer::new();
+        self.write(&mut buf)?;
+        Ok(buf.buf)
+    }
+
+    // RFC 2181 section 5.2: all records of an RRset share one TTL, so upstream
+    // inconsistencies are resolved by taking the minimum.
+    pub fn normalize_rrset_ttls(&mut self) {
+        for section in [&mut self.answers, &mut self.authority, &mut self.addtional].iter_mut() {
+            let mut min_ttls: Vec<((String, QueryType, u16), u32)> = Vec::new();
+            for record in section.iter() {
+                if let DNSRecord::OPT { .. } = record {
+                    continue;
+                }
+                let key = (
+                    normalize_name(record.get_name()),
+                    record.get_type(),
+                    record.get_class(),
+                );
+                match min_ttls.iter_mut().find(|(rrset, _)| *rrset == key) {
+                    Some((_, ttl)) => *ttl = (*ttl).min(record.get_ttl()),
+                    None => min_ttls.push((key, record.get_ttl())),
+                }
+            }
+            for record in section.iter_mut() {
+                let key = (
+                    normalize_name(record.get_name()),
+                    record.get_type(),
+                    record.get_class(),
+                );
+                if let Some((_, ttl)) = min_ttls.iter().find(|(rrset, _)| *rrset == key) {
+                    record.set_ttl(*ttl);
+                }
+            }
+        }
+    }
+
+    // Copy without the per-transaction EDNS data (OPT record, pending EDE), as
+    // stored in the cache
+    pub fn compacted(&self) -> DNSPacket {
+        let not_opt = |record: &&DNSRecord| !matches!(record, DNSRecord::OPT { .. });
+        DNSPacket {
+            header: self.header.clone(),
+            questions: self.questions.clone(),
+            answers: self.answers.iter().filter(not_opt).cloned().collect(),
+            authority: self.authority.iter().filter(not_opt).cloned().collect(),
+            addtional: self.addtional.iter().filter(not_opt).cloned().collect(),
+            ede: None,
+        }
+    }
+
+    // Enforces the header and section invariants of a response about to be sent.
+    // TC is cleared too, only `truncate` sets it once the response didn't fit.
+    pub fn sanitize(&mut self, ctx: &ResponseContext) {
+        self.header.query_response = true;
+        self.header.auth_answer = ctx.authoritative;
+        self.header.recur_available = ctx.recursion_available;
+        self.header.truncated_msg = false;
+        self.header.z = false;
+        // Nothing is validated here, so no answer is vouched for
+        self.header.authentic_data = false;
+        self.header.checking_disabled = ctx.checking_disabled;
+        let not_opt = |record: &DNSRecord| !matches!(record, DNSRecord::OPT { .. });
+        self.answers.retain(not_opt);
+        self.authority.retain(not_opt);
+        // An upstream's OPT belongs to its own transaction, its options and
+        // payload size mean nothing to our client (RFC 6891 section 7)
+        self.addtional.retain(not_opt);
+        if ctx.edns {
+            let flags = if ctx.dnssec_ok { EDNS_FLAG_DO } else { 0 };
+            self.set_edns(EDNS_VERSION, flags);
+            self.set_udp_payload_size(SERVER_UDP_PAYLOAD);
+        } else {
+            self.ede = None;
+            if self.header.res_code.to_num() > 0xF {
+                // Extended rcodes can't be expressed without an OPT record
+                self.header.res_code = RCode::SERVFAIL;
+            }
+        }
+        self.header.q_count = self.questions.len() as u16;
+        self.header.an_count = self.answers.len() as u16;
+        self.header.ns_count = self.authority.len() as u16;
+        self.header.ad_count = self.addtional.len() as u16;
+    }
+
+    // Keeps at most `max` additional records besides the OPT record. Addresses
+    // of names the answer and authority sections point at (NS glue, MX hosts)
+    // are kept before anything else.
+    pub fn trim_additional(&mut self, max: usize) {
+        let targets = self
+            .answers
+            .iter()
+            .chain(self.authority.iter())
+            .filter_map(|record| match record {
+                DNSRecord::NS { host, .. } | DNSRecord::MX { host, .. } => {
+                    Some(normalize_name(host))
+                }
+                _ => None,
+            })
+            .collect::<HashSet<String>>();
+        let (opt, records): (Vec<DNSRecord>, Vec<DNSRecord>) = self
+            .addtional
+            .drain(..)
+            .partition(|record| matches!(record, DNSRecord::OPT { .. }));
+        if records.len() <= max {
+            self.addtional = records;
+        } else {
+            let is_glue = |record: &DNSRecord| {
+                matches!(record, DNSRecord::A { .. } | DNSRecord::AAAA { .. })
+                    && targets.contains(&normalize_name(record.get_name()))
+            };
+            let (glue, rest): (Vec<DNSRecord>, Vec<DNSRecord>) =
+                records.into_iter().partition(is_glue);
+            self.addtional = glue.into_iter().chain(rest).take(max).collect();
+        }
+        self.addtional.extend(opt);
+        self.header.ad_count = self.addtional.len() as u16;
+    }
+
+    // Drops A/AAAA answers pointing at bogon addresses, returning how many went
+    pub fn drop_bogon_answers(&mut self) -> usize {
+        let before = self.answers.len();
+        self.answers.retain(|record| match record {
+            DNSRecord::A { addr, .. } => !is_bogon(IpAddr::V4(*addr)),
+            DNSRecord::AAAA { addr, .. } => !is_bogon(IpAddr::V6(*addr)),
+            _ => true,
+        });
+        self.header.an_count = self.answers.len() as u16;
+        before - self.answers.len()
+    }
+
+    pub fn dedup_answers(&mut self) {
+        for section in [&mut self.answers, &mut self.authority, &mut self.addtional].iter_mut() {
+            let mut unique: Vec<DNSRecord> = Vec::with_capacity(section.len());
+            for record in section.drain(..) {
+                if !unique.contains(&record) {
+                    unique.push(record);
+                }
+            }
+            **section = unique;
+        }
+        self.header.an_count = self.answers.len() as u16;
+        self.header.ns_count = self.authority.len() as u16;
+        self.header.ad_count = self.addtional.len() as u16;
+    }
+
+    // Differences between two packets' rcode and record sections. Records are
+    // compared without their TTLs, which depend on how long they were cached,
+    // and like any record comparison without their wire RDLENGTH.
+    pub fn diff(&self, other: &DNSPacket) -> Vec<PacketDiff> {
+        let mut diffs = Vec::new();
+        if self.header.res_code != other.header.res_code {
+            diffs.push(PacketDiff::RCode {
+                ours: self.header.res_code,
+                theirs: other.header.res_code,
+            });
+        }
+        let without_ttl = |records: &[DNSRecord]| {
+            records
+                .iter()
+                .map(|record| {
+                    let mut record = record.clone();
+                    record.set_ttl(0);
+                    record
+                })
+                .collect::<Vec<DNSRecord>>()
+        };
+        let sections = [
+            (Section::Answer, &self.answers, &other.answers),
+            (Section::Authority, &self.authority, &other.authority),
+            (Section::Additional, &self.addtional, &other.addtional),
+        ];
+        for (section, ours, theirs) in sections.iter() {
+            let ours = without_ttl(ours);
+            let theirs = without_ttl(theirs);
+            for record in ours.iter().filter(|record| !theirs.contains(record)) {
+                diffs.push(PacketDiff::Missing(*section, record.clone()));
+            }
+            for record in theirs.iter().filter(|record| !ours.contains(record)) {
+                diffs.push(PacketDiff::Extra(*section, record.clone()));
+            }
+        }
+        diffs
+    }
+
+    pub fn is_referral(&self) -> bool {
+        self.header.res_code == RCode::NOERROR
+            && self.answers.is_empty()
+            && self
+                .authority
+                .iter()
+                .any(|record| matches!(record, DNSRecord::NS { .. }))
+            && !self
+                .authority
+                .iter()
+                .any(|record| matches!(record, DNSRecord::SOA { .. }))
+    }
+
+    // The name exists but has no records of the asked type, which includes empty
+    // non-terminals. Told apart from a referral by the SOA in the authority section.
+    pub fn is_nodata(&self) -> bool {
+        self.header.res_code == RCode::NOERROR
+            && self.answers.is_empty()
+            && self
+                .authority
+                .iter()
+                .any(|record| matches!(record, DNSRecord::SOA { .. }))
+    }
+
+    // A response from a nameserver delegated `zone` that shows it doesn't actually
+    // serve it: refusing the query, answering non-authoritatively with nothing,
+    // or referring back up (or sideways) instead of further down the tree
+    pub fn is_lame_for(&self, zone: &str) -> bool {
+        if self.header.res_code == RCode::REFUSED {
+            return true;
+        }
+        if self.header.res_code != RCode::NOERROR
+            || !self.answers.is_empty()
+            || self.header.auth_answer
+            || self.header.recur_available
+        {
+            return false;
+        }
+        if self.is_referral() {
+            return self.authority.iter().any(|record| match record {
+                DNSRecord::NS { name, .. } => {
+                    !is_subdomain_of(name, zone) || normalize_name(name) == normalize_name(zone)
+                }
+                _ => false,
+            });
+        }
+        !self.is_nodata()
+    }
+
+    // A referral from a server that didn't recurse for us (RA=0) is only a step
+    // towards the answer, so it must not be cached as the answer itself.
+    pub fn is_final_answer(&self) -> bool {
+        !self.is_referral() || self.header.recur_available
+    }
+
+    // Host of the first PTR answer, i.e. the result of a reverse lookup
+    pub fn get_ptr(&self) -> Option<&str> {
+        self.answers.iter().find_map(|record| match record {
+            DNSRecord::PTR { host, .. } => Some(host.as_str()),
+            _ => None,
+        })
+    }
+
+    pub fn get_random_aaaa(&self) -> Option<Ipv6Addr> {
+        self.answers
+            .iter()
+            .filter_map(|record| match record {
+                DNSRecord::AAAA { addr, .. } => Some(*addr),
+                _ => None,
+            })
+            .next()
+    }
+
     pub fn get_random_a(&self) -> Option<Ipv4Addr> {
         self.answers
             .iter()
@@ -574,21 +2274,54 @@ impl DNSPacket {
                 DNSRecord::NS { name, host, .. } => Some((name.as_str(), host.as_str())),
                 _ => None,
             })
-            .filter(move |(name, _)| qname.ends_with(*name))
+            .filter(move |(name, _)| is_subdomain_of(qname, name))
+    }
+
+    pub fn get_all_resolved_ns<'a>(
+        &'a self,
+        qname: &'a str,
+    ) -> impl Iterator<Item = Ipv4Addr> + 'a {
+        self.get_ns(qname)
+            .flat_map(move |(_, host)| {
+                let host = normalize_name(host);
+                self.addtional
+                    .iter()
+                    .filter_map(move |record| match record {
+                        DNSRecord::A { name, addr, .. } if normalize_name(name) == host => {
+                            Some(addr)
+                        }
+                        _ => None,
+                    })
+            })
+            .copied()
     }
 
     pub fn get_resolved_ns(&self, qname: &str) -> Option<Ipv4Addr> {
+        self.get_all_resolved_ns(qname).next()
+    }
+
+    // Like `get_all_resolved_ns`, following AAAA glue
+    pub fn get_all_resolved_ns_v6<'a>(
+        &'a self,
+        qname: &'a str,
+    ) -> impl Iterator<Item = Ipv6Addr> + 'a {
         self.get_ns(qname)
-            .flat_map(|(_, host)| {
+            .flat_map(move |(_, host)| {
+                let host = normalize_name(host);
                 self.addtional
                     .iter()
                     .filter_map(move |record| match record {
-                        DNSRecord::A { name, addr, .. } if name == host => Some(addr),
+                        DNSRecord::AAAA { name, addr, .. } if normalize_name(name) == host => {
+                            Some(addr)
+                        }
                         _ => None,
                     })
             })
-            .map(|addr| *addr)
-            .next()
+            .copied()
+    }
+
+    pub fn get_resolved_ns_v6(&self, qname: &str) -> Option<Ipv6Addr> {
+        self.get_all_resolved_ns_v6(qname).next()
     }
 
     pub fn get_unresolved_ns<'a>(&'a self, qname: &'a str) -> Option<&'a str> {
@@ -602,4 +2335,696 @@ impl From<Vec<DNSRecord>> for DNSPacket {
         packet.answers = records;
         packet
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_records_keep_their_rdata() {
+        let record = DNSRecord::UNKNOWN {
+            name: "example".to_owned(),
+            q_type: QueryType::UNKNOWN(46),
+            class: CLASS_IN,
+            ttl: 300,
+            len: 3,
+            data: vec![1, 2, 3],
+        };
+        let mut packet = DNSPacket::new();
+        packet.answers.push(record.clone());
+        packet
+            .addtional
+            .push(DNSRecord::new_a("example", Ipv4Addr::new(192, 0, 2, 1), 60));
+        let parsed = DNSPacket::from_bytes(&packet.to_bytes().unwrap()).unwrap();
+        assert_eq!(parsed.answers, vec![record]);
+        assert_eq!(parsed.addtional.len(), 1);
+    }
+
+    #[test]
+    fn extended_error_travels_in_the_opt_record() {
+        let mut packet = DNSPacket::new();
+        packet.set_udp_payload_size(1232);
+        packet.header.res_code = RCode::SERVFAIL;
+        packet.ede = Some((
+            EDE_NO_REACHABLE_AUTHORITY,
+            "No reachable authority".to_owned(),
+        ));
+        let parsed = DNSPacket::from_bytes(&packet.to_bytes().unwrap()).unwrap();
+        let mut expected = EDE_NO_REACHABLE_AUTHORITY.to_be_bytes().to_vec();
+        expected.extend_from_slice(b"No reachable authority");
+        assert_eq!(parsed.edns_option(EDNS_OPTION_EDE), Some(&expected[..]));
+    }
+
+    fn response_context(edns: bool, dnssec_ok: bool) -> ResponseContext {
+        ResponseContext {
+            edns,
+            authoritative: false,
+            recursion_available: true,
+            dnssec_ok,
+            checking_disabled: false,
+        }
+    }
+
+    // An upstream answer still carrying the upstream's OPT record, cookie and all
+    fn upstream_answer() -> DNSPacket {
+        let mut packet = DNSPacket::new();
+        packet
+            .answers
+            .push(DNSRecord::new_a("example", Ipv4Addr::new(192, 0, 2, 1), 60));
+        packet.set_udp_payload_size(4096);
+        packet.add_edns_option(10, vec![1, 2, 3, 4, 5, 6, 7, 8]);
+        packet
+    }
+
+    #[test]
+    fn sanitize_replaces_the_upstream_opt_with_our_own() {
+        let mut packet = upstream_answer();
+        packet.sanitize(&response_context(true, false));
+        let opts = packet
+            .addtional
+            .iter()
+            .filter(|record| record.get_type() == QueryType::OPT)
+            .count();
+        assert_eq!(opts, 1);
+        assert_eq!(packet.udp_payload_size(), Some(SERVER_UDP_PAYLOAD));
+        assert_eq!(packet.edns_option(10), None);
+        assert!(!packet.dnssec_ok());
+    }
+
+    #[test]
+    fn sanitize_drops_opt_for_clients_without_edns() {
+        let mut packet = upstream_answer();
+        packet.ede = Some((EDE_BLOCKED, "Blocked".to_owned()));
+        packet.sanitize(&response_context(false, false));
+        assert!(packet.get_opt().is_none());
+        assert!(packet.ede.is_none());
+        assert_eq!(packet.header.ad_count, 0);
+    }
+
+    #[test]
+    fn sanitize_adds_an_opt_to_answers_without_one() {
+        // Answers served from the cache have had their OPT record removed
+        let mut packet = upstream_answer().compacted();
+        packet.sanitize(&response_context(true, false));
+        assert_eq!(packet.udp_payload_size(), Some(SERVER_UDP_PAYLOAD));
+    }
+
+    #[test]
+    fn sanitize_echoes_do_and_cd_and_clears_ad() {
+        let mut packet = upstream_answer().compacted();
+        packet.header.authentic_data = true;
+        packet.sanitize(&ResponseContext {
+            checking_disabled: true,
+            ..response_context(true, true)
+        });
+        assert!(packet.dnssec_ok());
+        assert!(packet.header.checking_disabled);
+        assert!(!packet.header.authentic_data);
+    }
+
+    #[test]
+    fn header_keeps_z_ad_and_cd_apart() {
+        let mut packet = DNSPacket::new();
+        packet.header.authentic_data = true;
+        packet.header.checking_disabled = true;
+        let parsed = DNSPacket::from_bytes(&packet.to_bytes().unwrap()).unwrap();
+        assert!(!parsed.header.z);
+        assert!(parsed.header.authentic_data);
+        assert!(parsed.header.checking_disabled);
+    }
+
+    #[test]
+    fn sanitized_response_counts_match_its_records() {
+        let mut packet = upstream_answer();
+        let opt = packet.get_opt().cloned().unwrap();
+        packet.authority.push(opt);
+        packet.answers.push(DNSRecord::UNKNOWN {
+            name: "example".to_owned(),
+            q_type: QueryType::UNKNOWN(46),
+            class: CLASS_IN,
+            ttl: 60,
+            len: 2,
+            data: vec![0, 1],
+        });
+        packet.header.truncated_msg = true;
+        packet.sanitize(&response_context(true, false));
+        assert!(packet.header.query_response);
+        assert!(!packet.header.truncated_msg);
+        assert!(packet.authority.is_empty());
+        let parsed = DNSPacket::from_bytes(&packet.to_bytes().unwrap()).unwrap();
+        assert_eq!(parsed.header.an_count, 2);
+        assert_eq!(parsed.answers.len(), 2);
+        assert_eq!(parsed.header.ad_count, 1);
+    }
+
+    // A response with the given answer records and nothing else
+    fn raw_answers(records: &[Vec<u8>]) -> Vec<u8> {
+        let mut bytes = vec![0, 1, 0x80, 0, 0, 0, 0, records.len() as u8, 0, 0, 0, 0];
+        for record in records {
+            bytes.extend_from_slice(record);
+        }
+        bytes
+    }
+
+    // Record owned by the root with a declared RDLENGTH that may not match its data
+    fn raw_record(q_type: u16, rdlength: u16, rdata: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![0];
+        bytes.extend_from_slice(&q_type.to_be_bytes());
+        bytes.extend_from_slice(&CLASS_IN.to_be_bytes());
+        bytes.extend_from_slice(&300u32.to_be_bytes());
+        bytes.extend_from_slice(&rdlength.to_be_bytes());
+        bytes.extend_from_slice(rdata);
+        bytes
+    }
+
+    fn round_trip(record: DNSRecord) -> DNSRecord {
+        let mut packet = DNSPacket::new();
+        packet.answers.push(record);
+        let mut parsed = DNSPacket::from_bytes(&packet.to_bytes().unwrap()).unwrap();
+        parsed.answers.pop().unwrap()
+    }
+
+    // "example. NS ns1.example." spelled out in full, then with its owner and the
+    // host's "example" suffix compressed to pointers at the first owner name
+    fn compressed_and_uncompressed_ns() -> Vec<u8> {
+        let mut bytes = vec![0, 1, 0x80, 0, 0, 0, 0, 2, 0, 0, 0, 0];
+        let ns_header = |rdlength: u8| vec![0, 2, 0, 1, 0, 0, 1, 44, 0, rdlength];
+        bytes.extend_from_slice(b"\x07example\x00");
+        bytes.extend(ns_header(13));
+        bytes.extend_from_slice(b"\x03ns1\x07example\x00");
+        bytes.extend_from_slice(&[0xC0, 12]);
+        bytes.extend(ns_header(6));
+        bytes.extend_from_slice(b"\x03ns1\xC0\x0C");
+        bytes
+    }
+
+    #[test]
+    fn records_are_equal_however_their_names_were_compressed() {
+        let packet = DNSPacket::from_bytes(&compressed_and_uncompressed_ns()).unwrap();
+        let (full, compressed) = (&packet.answers[0], &packet.answers[1]);
+        assert!(matches!(full, DNSRecord::NS { len: 13, .. }));
+        assert!(matches!(compressed, DNSRecord::NS { len: 6, .. }));
+        assert_eq!(full, compressed);
+
+        let mut other_ttl = compressed.clone();
+        other_ttl.set_ttl(60);
+        assert_ne!(full, &other_ttl);
+    }
+
+    #[test]
+    fn duplicates_with_different_lengths_are_collapsed() {
+        let mut packet = DNSPacket::from_bytes(&compressed_and_uncompressed_ns()).unwrap();
+        packet.dedup_answers();
+        assert_eq!(packet.answers.len(), 1);
+        assert_eq!(packet.header.an_count, 1);
+    }
+
+    #[test]
+    fn diff_ignores_how_names_were_compressed() {
+        let parsed = DNSPacket::from_bytes(&compressed_and_uncompressed_ns()).unwrap();
+        let primary = DNSPacket::from(vec![parsed.answers[0].clone()]);
+        let mut secondary = DNSPacket::from(vec![parsed.answers[1].clone()]);
+        secondary.answers[0].set_ttl(100);
+        assert!(primary.diff(&secondary).is_empty());
+    }
+
+    #[test]
+    fn eui48_needs_exactly_six_bytes() {
+        let a = raw_record(1, 4, &[192, 0, 2, 1]);
+        let bytes = raw_answers(&[raw_record(108, 4, &[0, 1, 2, 3]), a]);
+        assert!(DNSPacket::from_bytes(&bytes).is_err());
+
+        let mut buf = VecBuffer::new();
+        buf.buf = bytes;
+        let (packet, warnings) = DNSPacket::from_buffer_lenient(&mut buf).unwrap();
+        assert_eq!(packet.answers.len(), 1);
+        assert_eq!(packet.answers[0].get_type(), QueryType::A);
+        assert!(matches!(
+            warnings.as_slice(),
+            [ParseWarning::MalformedRecord(Section::Answer, _)]
+        ));
+    }
+
+    #[test]
+    fn lenient_parse_keeps_what_it_can_and_warns_about_the_rest() {
+        let mut bytes = raw_answers(&[
+            raw_record(108, 4, &[0, 1, 2, 3]),
+            raw_record(65280, 2, &[0xAB, 0xCD]),
+            raw_record(1, 4, &[192, 0, 2, 1]),
+        ]);
+        // An additional record the packet ends before
+        bytes[11] = 1;
+        let mut buf = VecBuffer::new();
+        buf.buf = bytes;
+        let (packet, warnings) = DNSPacket::from_buffer_lenient(&mut buf).unwrap();
+        let types = packet
+            .answers
+            .iter()
+            .map(DNSRecord::get_type)
+            .collect::<Vec<_>>();
+        assert_eq!(types, [QueryType::UNKNOWN(65280), QueryType::A]);
+        assert!(matches!(
+            warnings.as_slice(),
+            [
+                ParseWarning::MalformedRecord(Section::Answer, _),
+                ParseWarning::UnknownType(Section::Answer, 65280),
+                ParseWarning::Truncated(Section::Additional),
+            ]
+        ));
+    }
+
+    #[test]
+    fn eui64_needs_exactly_eight_bytes() {
+        let bytes = raw_answers(&[raw_record(109, 10, &[0; 10])]);
+        assert!(DNSPacket::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn rdata_shorter_than_its_fixed_fields_is_rejected() {
+        // CDS, CDNSKEY, CERT, WKS, CSYNC and ZONEMD, each a byte too short
+        for (q_type, fixed) in [(59, 4), (60, 4), (37, 5), (11, 5), (62, 6), (63, 6)].iter() {
+            let rdata = vec![0; fixed - 1];
+            let bytes = raw_answers(&[
+                raw_record(*q_type, rdata.len() as u16, &rdata),
+                raw_record(1, 4, &[192, 0, 2, 1]),
+            ]);
+            assert!(DNSPacket::from_bytes(&bytes).is_err(), "type {}", q_type);
+        }
+    }
+
+    #[test]
+    fn edns_option_may_not_overrun_the_opt_rdata() {
+        // Option 10 claims 8 bytes but the RDATA ends after 2 of them, right
+        // where the next record starts
+        let opt = raw_record(41, 6, &[0, 10, 0, 8, 1, 2]);
+        let bytes = raw_answers(&[opt, raw_record(1, 4, &[192, 0, 2, 1])]);
+        assert!(DNSPacket::from_bytes(&bytes).is_err());
+
+        let opt = raw_record(41, 3, &[0, 10, 0]);
+        let bytes = raw_answers(&[opt, raw_record(1, 4, &[192, 0, 2, 1])]);
+        assert!(DNSPacket::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn packets_declaring_too_many_records_are_rejected() {
+        let a = raw_record(1, 4, &[192, 0, 2, 1]);
+        let mut bytes = raw_answers(&[a.clone(), a]);
+        let mut buf = VecBuffer::new();
+        buf.buf = bytes.clone();
+        assert!(DNSPacket::from_buffer_with_limit(&mut buf, 1).is_err());
+        buf.seek(0).unwrap();
+        assert_eq!(
+            DNSPacket::from_buffer_with_limit(&mut buf, 2)
+                .unwrap()
+                .answers
+                .len(),
+            2
+        );
+
+        // 0xFFFF claimed answers are refused before any record is read
+        bytes[6..8].copy_from_slice(&[0xFF, 0xFF]);
+        let err = DNSPacket::from_bytes(&bytes).unwrap_err();
+        assert!(err.to_string().contains("more than the limit"));
+    }
+
+    #[test]
+    fn serials_compare_across_wraparound() {
+        assert!(serial_newer(2, 1));
+        assert!(!serial_newer(1, 2));
+        assert!(!serial_newer(7, 7));
+        assert!(serial_newer(5, u32::MAX - 5));
+        assert!(!serial_newer(u32::MAX - 5, 5));
+        assert!(serial_newer(0, u32::MAX));
+        // Exactly half the number space apart is undefined, treated as not newer
+        assert!(!serial_newer(1 << 31, 0));
+        assert!(serial_newer((1 << 31) - 1, 0));
+    }
+
+    #[test]
+    fn set_question_replaces_the_question_section() {
+        let mut packet = DNSPacket::new()
+            .with_question(DNSQuestion::new("old.example".to_owned(), QueryType::A));
+        packet.add_question(DNSQuestion::new("older.example".to_owned(), QueryType::MX));
+        packet.set_question(DNSQuestion::new("new.example".to_owned(), QueryType::AAAA));
+        assert_eq!(packet.header.q_count, 1);
+        assert_eq!(packet.questions.len(), 1);
+        assert_eq!(packet.questions[0].name, "new.example");
+        assert_eq!(packet.questions[0].q_type, QueryType::AAAA);
+    }
+
+    #[test]
+    fn trimmed_additional_section_keeps_glue() {
+        let mut packet = DNSPacket::new();
+        packet.answers.push(DNSRecord::MX {
+            name: "example".to_owned(),
+            q_type: QueryType::MX,
+            class: CLASS_IN,
+            ttl: 300,
+            len: 0,
+            priority: 10,
+            host: "mail.example".to_owned(),
+        });
+        packet.authority.push(DNSRecord::NS {
+            name: "example".to_owned(),
+            q_type: QueryType::NS,
+            class: CLASS_IN,
+            ttl: 300,
+            len: 0,
+            host: "ns1.example".to_owned(),
+        });
+        for i in 1..=5 {
+            let name = format!("extra{}.example", i);
+            packet
+                .addtional
+                .push(DNSRecord::new_a(&name, Ipv4Addr::new(192, 0, 2, i), 300));
+        }
+        let glue = vec![
+            DNSRecord::new_a("MAIL.example.", Ipv4Addr::new(192, 0, 2, 25), 300),
+            DNSRecord::new_a("ns1.example", Ipv4Addr::new(192, 0, 2, 53), 300),
+        ];
+        packet.addtional.extend(glue.clone());
+        packet.set_edns(0, 0);
+
+        packet.trim_additional(2);
+        assert_eq!(packet.addtional[..2], glue[..]);
+        assert!(matches!(packet.addtional[2], DNSRecord::OPT { .. }));
+        assert_eq!(packet.header.ad_count, 3);
+    }
+
+    #[test]
+    fn qclass_any_questions_round_trip() {
+        let mut question = DNSQuestion::new("example.com".to_owned(), QueryType::A);
+        question.class = QueryClass::ANY;
+        let mut packet = DNSPacket::new().with_question(question);
+        let bytes = packet.to_bytes().unwrap();
+        assert_eq!(bytes[bytes.len() - 2..], [0, 255]);
+        let parsed = DNSPacket::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed.questions[0].class, QueryClass::ANY);
+    }
+
+    #[test]
+    fn opt_options_are_found_by_code() {
+        let rdata = [
+            0, 10, 0, 8, 1, 2, 3, 4, 5, 6, 7, 8, // COOKIE
+            0, 12, 0, 2, 0, 0, // PADDING
+        ];
+        let mut bytes = raw_answers(&[raw_record(41, rdata.len() as u16, &rdata)]);
+        // Moved from the answer count to the additional count
+        bytes[7] = 0;
+        bytes[11] = 1;
+        let packet = DNSPacket::from_bytes(&bytes).unwrap();
+        assert_eq!(packet.edns_option(10), Some(&[1, 2, 3, 4, 5, 6, 7, 8][..]));
+        assert_eq!(packet.edns_option(12), Some(&[0, 0][..]));
+        assert_eq!(packet.edns_option(15), None);
+    }
+
+    #[test]
+    fn txt_strings_stay_within_their_rdata() {
+        // RDLENGTH 3 but the string claims 5 bytes, reading on would eat the A record
+        let txt = raw_record(16, 3, &[5, b'h', b'e', b'l', b'l', b'o']);
+        let bytes = raw_answers(&[txt, raw_record(1, 4, &[192, 0, 2, 1])]);
+        assert!(DNSPacket::from_bytes(&bytes).is_err());
+
+        let empty_strings = vec![0; buffer::MAX_CHARACTER_STRINGS + 1];
+        let txt = raw_record(16, empty_strings.len() as u16, &empty_strings);
+        assert!(DNSPacket::from_bytes(&raw_answers(&[txt])).is_err());
+    }
+
+    #[test]
+    fn eui_txt_and_caa_records_round_trip() {
+        let records = vec![
+            DNSRecord::EUI48 {
+                name: "host.example".to_owned(),
+                q_type: QueryType::EUI48,
+                class: CLASS_IN,
+                ttl: 300,
+                len: 6,
+                address: [0x00, 0x00, 0x5e, 0x00, 0x53, 0x2a],
+            },
+            DNSRecord::EUI64 {
+                name: "host.example".to_owned(),
+                q_type: QueryType::EUI64,
+                class: CLASS_IN,
+                ttl: 300,
+                len: 8,
+                address: [0x00, 0x00, 0x5e, 0xef, 0x10, 0x00, 0x00, 0x2a],
+            },
+            DNSRecord::TXT {
+                name: "example".to_owned(),
+                q_type: QueryType::TXT,
+                class: CLASS_IN,
+                ttl: 300,
+                len: 13,
+                data: vec!["v=spf1".to_owned(), "-all".to_owned(), "".to_owned()],
+            },
+            DNSRecord::CAA {
+                name: "example".to_owned(),
+                q_type: QueryType::CAA,
+                class: CLASS_IN,
+                ttl: 300,
+                len: 17,
+                flags: 128,
+                tag: "issue".to_owned(),
+                value: "ca.example".to_owned(),
+            },
+        ];
+        for record in records {
+            assert_eq!(round_trip(record.clone()), record);
+        }
+    }
+
+    #[test]
+    fn cds_and_cdnskey_records_round_trip() {
+        let cds = DNSRecord::CDS {
+            name: "example".to_owned(),
+            q_type: QueryType::CDS,
+            class: CLASS_IN,
+            ttl: 3600,
+            len: 8,
+            key_tag: 60485,
+            algorithm: 5,
+            digest_type: 1,
+            digest: vec![0x2b, 0xb1, 0x83, 0xaf],
+        };
+        let cdnskey = DNSRecord::CDNSKEY {
+            name: "example".to_owned(),
+            q_type: QueryType::CDNSKEY,
+            class: CLASS_IN,
+            ttl: 3600,
+            len: 7,
+            flags: 257,
+            protocol: 3,
+            algorithm: 13,
+            public_key: vec![1, 2, 3],
+        };
+        assert_eq!(round_trip(cds.clone()), cds);
+        assert_eq!(round_trip(cdnskey.clone()), cdnskey);
+    }
+
+    fn referral(zone: &str, host: &str, glue_owner: &str) -> DNSPacket {
+        let mut packet = DNSPacket::new();
+        packet.authority.push(DNSRecord::NS {
+            name: zone.to_owned(),
+            q_type: QueryType::NS,
+            class: CLASS_IN,
+            ttl: 3600,
+            len: 0,
+            host: host.to_owned(),
+        });
+        packet.addtional.push(DNSRecord::new_a(
+            glue_owner,
+            Ipv4Addr::new(192, 0, 2, 53),
+            3600,
+        ));
+        packet.addtional.push(DNSRecord::new_aaaa(
+            glue_owner,
+            "2001:db8::53".parse().unwrap(),
+            3600,
+        ));
+        packet
+    }
+
+    #[test]
+    fn glue_matches_ns_targets_regardless_of_case() {
+        let packet = referral("Example.", "NS1.example.", "ns1.EXAMPLE");
+        assert_eq!(
+            packet.get_resolved_ns("www.example"),
+            Some(Ipv4Addr::new(192, 0, 2, 53))
+        );
+        assert_eq!(packet.get_all_resolved_ns_v6("WWW.example").count(), 1);
+        assert_eq!(
+            referral("example", "ns1.example", "ns2.example").get_resolved_ns("www.example"),
+            None
+        );
+        // A zone only covers names below it, not every name ending in the same letters
+        assert_eq!(packet.get_resolved_ns("notexample"), None);
+    }
+
+    #[test]
+    fn rrset_ttls_are_normalized_to_their_minimum() {
+        let mut packet = DNSPacket::new();
+        packet.answers = vec![
+            DNSRecord::new_a("www.example", Ipv4Addr::new(192, 0, 2, 1), 300),
+            DNSRecord::new_a("WWW.example.", Ipv4Addr::new(192, 0, 2, 2), 60),
+            DNSRecord::new_a("mail.example", Ipv4Addr::new(192, 0, 2, 3), 600),
+        ];
+        packet.normalize_rrset_ttls();
+        let ttls = packet
+            .answers
+            .iter()
+            .map(|record| record.get_ttl())
+            .collect::<Vec<u32>>();
+        assert_eq!(ttls, vec![60, 60, 600]);
+    }
+
+    #[test]
+    fn requests_are_classified_before_dispatch() {
+        let query = DNSPacket::new()
+            .with_question(DNSQuestion::new("www.example".to_owned(), QueryType::A));
+        assert_eq!(query.classify_request(), RequestKind::Standard);
+
+        let mut update = query.clone();
+        update.header.opcode = OpCode::UPDATE;
+        assert_eq!(update.classify_request(), RequestKind::Update);
+
+        assert_eq!(
+            DNSPacket::new().classify_request(),
+            RequestKind::Malformed(FormErrReason::NoQuestion)
+        );
+        let mut response = query.clone();
+        response.header.query_response = true;
+        assert_eq!(
+            response.classify_request(),
+            RequestKind::Malformed(FormErrReason::NotAQuery)
+        );
+    }
+
+    #[test]
+    fn referral_without_recursion_is_not_a_final_answer() {
+        let mut packet = referral("example", "ns1.example", "ns1.example");
+        assert!(packet.is_referral());
+        assert!(!packet.is_final_answer());
+        // A recursive server handing out NS records did all the work it will do
+        packet.header.recur_available = true;
+        assert!(packet.is_final_answer());
+        assert!(DNSPacket::from(vec![DNSRecord::new_a(
+            "www.example",
+            Ipv4Addr::new(192, 0, 2, 1),
+            300
+        )])
+        .is_final_answer());
+    }
+
+    #[test]
+    fn csync_records_round_trip() {
+        // RFC 7477 section 2.3: "66 3 A NS AAAA", immediate and soaminimum set
+        let csync = DNSRecord::CSYNC {
+            name: "example.com".to_owned(),
+            q_type: QueryType::CSYNC,
+            class: CLASS_IN,
+            ttl: 3600,
+            len: 12,
+            soa_serial: 66,
+            flags: 3,
+            type_bitmap: vec![0, 4, 0x60, 0, 0, 0x08],
+        };
+        assert_eq!(round_trip(csync.clone()), csync);
+    }
+
+    #[test]
+    fn zonemd_records_round_trip() {
+        // Simple scheme (1) with a SHA-384 (1) digest
+        let zonemd = DNSRecord::ZONEMD {
+            name: "example".to_owned(),
+            q_type: QueryType::ZONEMD,
+            class: CLASS_IN,
+            ttl: 86400,
+            len: 54,
+            serial: 2018031900,
+            scheme: 1,
+            hash_algorithm: 1,
+            digest: (0..48).collect(),
+        };
+        assert_eq!(round_trip(zonemd.clone()), zonemd);
+    }
+
+    #[test]
+    fn wks_records_round_trip() {
+        // TCP (6) with ports 21, 23 and 25 set in the service bitmap
+        let wks = DNSRecord::WKS {
+            name: "host.example".to_owned(),
+            q_type: QueryType::WKS,
+            class: CLASS_IN,
+            ttl: 3600,
+            len: 9,
+            addr: Ipv4Addr::new(192, 0, 2, 1),
+            protocol: 6,
+            bitmap: vec![0, 0, 0b0000_0101, 0b0100_0000],
+        };
+        assert_eq!(round_trip(wks.clone()), wks);
+    }
+
+    #[test]
+    fn cert_records_round_trip() {
+        let cert = DNSRecord::CERT {
+            name: "example".to_owned(),
+            q_type: QueryType::CERT,
+            class: CLASS_IN,
+            ttl: 3600,
+            len: 9,
+            cert_type: 1,
+            key_tag: 12345,
+            algorithm: 8,
+            certificate: vec![0x30, 0x82, 0x01, 0x0a],
+        };
+        assert_eq!(round_trip(cert.clone()), cert);
+    }
+
+    #[test]
+    fn duplicate_records_are_collapsed() {
+        let a = DNSRecord::new_a("www.example", Ipv4Addr::new(192, 0, 2, 1), 300);
+        let b = DNSRecord::new_a("www.example", Ipv4Addr::new(192, 0, 2, 2), 300);
+        let mut packet = DNSPacket::new();
+        packet.answers = vec![a.clone(), b.clone(), a.clone(), b.clone()];
+        packet.addtional = vec![a.clone(), a.clone()];
+        packet.dedup_answers();
+        assert_eq!(packet.answers, vec![a.clone(), b]);
+        assert_eq!(packet.addtional, vec![a]);
+        assert_eq!(packet.header.an_count, 2);
+        assert_eq!(packet.header.ad_count, 1);
+    }
+
+    #[test]
+    fn diff_reports_differing_records_and_rcodes() {
+        let ours = DNSRecord::new_a("www.example", Ipv4Addr::new(192, 0, 2, 1), 300);
+        let theirs = DNSRecord::new_a("www.example", Ipv4Addr::new(192, 0, 2, 2), 300);
+        let primary = DNSPacket::from(vec![ours.clone()]);
+        let mut secondary = DNSPacket::from(vec![theirs]);
+        // Reported without their TTLs
+        assert_eq!(
+            primary.diff(&secondary),
+            vec![
+                PacketDiff::Missing(
+                    Section::Answer,
+                    DNSRecord::new_a("www.example", Ipv4Addr::new(192, 0, 2, 1), 0)
+                ),
+                PacketDiff::Extra(
+                    Section::Answer,
+                    DNSRecord::new_a("www.example", Ipv4Addr::new(192, 0, 2, 2), 0)
+                ),
+            ]
+        );
+        // A record cached for a while is still the same record
+        secondary.answers = vec![ours];
+        secondary.answers[0].set_ttl(12);
+        secondary.header.res_code = RCode::SERVFAIL;
+        assert_eq!(
+            primary.diff(&secondary),
+            vec![PacketDiff::RCode {
+                ours: RCode::NOERROR,
+                theirs: RCode::SERVFAIL
+            }]
+        );
+    }
+}
@@ -1,138 +1,302 @@
 use buffer::{ArrayBuffer, PacketBufferTrait, VecBuffer};
 use diglett::*;
-use eyre::Result;
-use futures::future::BoxFuture;
+use eyre::{eyre, Result};
+use socket2::{Domain, Protocol, Socket, Type};
+use futures::future::join_all;
+use std::convert::Infallible;
 use std::net;
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
-use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::net::{SocketAddr, ToSocketAddrs};
 use tokio::net::{TcpListener, TcpStream, UdpSocket};
-use cache::DNSCache;
+use tokio::task::JoinHandle;
+use hyper::body::HttpBody;
+use hyper::header::{HeaderValue, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use cache::{DNSCache, Dedup, ResponseDedup};
+use config::ServerConfig;
+use resolver::{query_server, resolve_with_stats, Overloaded, QueryOptions, ReqProtocol};
+use metrics::run_statsd_emitter;
 
-async fn udp_lookup(qname: &str, q_type: QueryType, server: (Ipv4Addr, u16)) -> Result<DNSPacket> {
-    let mut socket = UdpSocket::bind(("0.0.0.0", 9999)).await?;
-    let mut dns_packet = DNSPacket::new();
-    dns_packet.header.id = 6996;
-    dns_packet.header.recur_desired = true;
-    dns_packet.add_question(DNSQuestion::new(qname.to_owned(), q_type));
-    let mut req_buf = ArrayBuffer::new();
-
-    dns_packet.write(&mut req_buf)?;
-
-    socket
-        .send_to(&req_buf.buf[0..req_buf.pos()], server)
-        .await?;
-
-    let mut res_buf = ArrayBuffer::new();
-    socket.recv_from(&mut res_buf.buf).await?;
-    let res_packet = DNSPacket::from_buffer(&mut res_buf)?;
-    Ok(res_packet)
+// Referral glue and authority data legitimately vary between resolvers, only
+// the rcode and answers have to agree
+fn mirror_divergence(primary: &DNSPacket, secondary: &DNSPacket) -> Vec<PacketDiff> {
+    primary
+        .diff(secondary)
+        .into_iter()
+        .filter(|diff| match diff {
+            PacketDiff::RCode { .. } => true,
+            PacketDiff::Missing(section, _) | PacketDiff::Extra(section, _) => {
+                *section == Section::Answer
+            }
+        })
+        .collect()
 }
 
-async fn tcp_lookup(qname: &str, q_type: QueryType, server: (Ipv4Addr, u16)) -> Result<DNSPacket> {
-    let mut socket = TcpStream::connect(server).await?;
-    let mut dns_packet = DNSPacket::new();
-    dns_packet.header.id = 6996;
-    dns_packet.header.recur_desired = true;
-    dns_packet.add_question(DNSQuestion::new(qname.to_owned(), q_type));
-    let mut req_buf = VecBuffer::new();
-
-    dns_packet.write(&mut req_buf)?;
-    req_buf.to_socket(&mut socket).await?;
-
-    let mut res_buf = VecBuffer::from_socket(&mut socket).await?;
-    let res_packet = DNSPacket::from_buffer(&mut res_buf)?;
-    Ok(res_packet)
+fn mirror_query(
+    question: DNSQuestion,
+    primary: DNSPacket,
+    server: SocketAddr,
+    options: QueryOptions,
+) {
+    tokio::spawn(async move {
+        match query_server(&question.name, question.q_type, server, ReqProtocol::UDP, &options).await {
+            Ok(secondary) => {
+                let diffs = mirror_divergence(&primary, &secondary);
+                if !diffs.is_empty() {
+                    println!(
+                        "Mirror {} diverged for {:?} {}: {:?}",
+                        server, question.q_type, question.name, diffs
+                    );
+                }
+            }
+            Err(err) => println!("Mirror lookup against {} failed: {}", server, err),
+        }
+    });
 }
 
-fn recursive_lookup<'a>(
-    qname: &'a str,
-    q_type: QueryType,
+async fn build_response(
+    request_packet: DNSPacket,
     protocol: ReqProtocol,
-    cache: &'a mut DNSCache
-) -> BoxFuture<'a , Result<DNSPacket>> {
-    Box::pin(async move {
-        let mut ns = "198.41.0.4".parse::<Ipv4Addr>()?;
-        if let Some(result) = cache.get_records(qname, q_type) {
-            println!("Found result in cache");
-            return Ok(result);
-        }
-        // } else {
-        //     if let Some(a_record) = cache.get_nearest_a_record(qname, q_type) {
-        //         ns = a_record;
-        //         println!("Chaning NS using cache");
-        //     }
-        // }
-        loop {
-            println!("attempting lookup of {:?} {} with ns {}", q_type, qname, ns);
+    cache: DNSCache,
+    config: &ServerConfig,
+) -> DNSPacket {
+    config.metrics.incr("queries");
+    // Extended errors ride in an OPT record, which is only allowed if the client sent one
+    let client_edns = request_packet.get_opt().is_some();
+    let dnssec_ok = request_packet.dnssec_ok();
+    let checking_disabled = request_packet.header.checking_disabled;
+    let (mut res_packet, authoritative) =
+        answer_request(request_packet, client_edns, protocol, cache, config).await;
+    res_packet.dedup_answers();
+    if let Some(max) = config.max_additional {
+        res_packet.trim_additional(max);
+    }
+    res_packet.sanitize(&ResponseContext {
+        edns: client_edns,
+        authoritative,
+        recursion_available: true,
+        dnssec_ok,
+        checking_disabled,
+    });
+    config.metrics.incr(&format!("rcode.{:?}", res_packet.header.res_code).to_lowercase());
+    res_packet
+}
 
-            let server = (ns.clone(), 53);
+// The response to a request and whether it was answered from local data the
+// server is authoritative for
+async fn answer_request(
+    mut request_packet: DNSPacket,
+    client_edns: bool,
+    protocol: ReqProtocol,
+    mut cache: DNSCache,
+    config: &ServerConfig,
+) -> (DNSPacket, bool) {
+    let mut res_packet = DNSPacket::new();
+    let mut authoritative = false;
 
-            let response = match protocol {
-                ReqProtocol::UDP => udp_lookup(qname, q_type, server).await?,
-                ReqProtocol::TCP => tcp_lookup(qname, q_type, server).await?,
-            };
+    res_packet.header.id = request_packet.header.id;
+    // Echoed even when refused, so the client can match a NOTIMP to its UPDATE or NOTIFY
+    res_packet.header.opcode = request_packet.header.opcode;
+    res_packet.header.recur_desired = request_packet.header.recur_desired;
 
-            cache.set_records(qname, q_type, response.clone());
+    let question = match request_packet.classify_request() {
+        RequestKind::Standard => request_packet.questions.remove(0),
+        RequestKind::Malformed(reason) => {
+            println!("Malformed request: {:?}", reason);
+            res_packet.header.res_code = RCode::FORMERR;
+            return (res_packet, authoritative);
+        }
+        kind => {
+            println!("Unsupported request: {:?}", kind);
+            res_packet.questions = request_packet.questions;
+            res_packet.header.res_code = RCode::NOTIMP;
+            return (res_packet, authoritative);
+        }
+    };
+    println!("Recieved Question: {}", question);
 
-            if !response.answers.is_empty() && response.header.res_code == RCode::NOERROR {
-                return Ok(response);
-            }
+    if question.class == QueryClass::ANY {
+        // Only UPDATE gives QCLASS ANY a meaning, and UPDATE is not supported
+        println!("Unsupported QCLASS ANY for {}", question.name);
+        res_packet.header.res_code = RCode::NOTIMP;
+        res_packet.set_question(question);
+        return (res_packet, authoritative);
+    }
 
-            if response.header.res_code == RCode::NXDOMAIN {
-                return Ok(response);
+    if question.class != QueryClass::IN {
+        // Nothing outside IN is resolved, CHAOS only gets the server's version
+        match config.chaos_answer(&question.name, question.q_type) {
+            Some(record) if question.class == QueryClass::CH => {
+                authoritative = true;
+                res_packet.answers.push(record);
             }
-
-            if let Some(new_ns) = response.get_resolved_ns(qname) {
-                ns = new_ns;
-                continue;
+            _ => {
+                println!("Refusing {} question for {}", question.class, question.name);
+                res_packet.header.res_code = RCode::REFUSED;
             }
+        }
+        res_packet.set_question(question);
+        return (res_packet, authoritative);
+    }
 
-            let new_ns_name = match response.get_unresolved_ns(qname) {
-                Some(name) => name,
-                None => return Ok(response),
-            };
+    if let Some(version) = request_packet.edns_version() {
+        if version > EDNS_VERSION {
+            println!("Unsupported EDNS version {} for {}", version, question.name);
+            res_packet.set_question(question);
+            res_packet.set_edns(EDNS_VERSION, 0);
+            res_packet.set_extended_rcode(RCODE_BADVERS);
+            return (res_packet, authoritative);
+        }
+    }
 
-            let recursive_response = recursive_lookup(new_ns_name, QueryType::A, protocol, cache).await?;
+    if name_wire_len(&question.name) > config.max_qname_len {
+        println!("Refusing over-long question for {}", question.name);
+        res_packet.header.res_code = RCode::REFUSED;
+        res_packet.set_question(question);
+    } else if !config.is_type_allowed(question.q_type) {
+        println!("Refusing disallowed type {:?} for {}", question.q_type, question.name);
+        res_packet.header.res_code = RCode::REFUSED;
+        res_packet.set_question(question);
+    } else if let Some(record) = config
+        .local_ptr_record(&question.name)
+        .filter(|_| question.q_type == QueryType::PTR)
+    {
+        // Configured names for the server's own addresses win over the built-in localhost
+        authoritative = true;
+        res_packet.answers.push(record);
+        res_packet.set_question(question);
+    } else if let Some(local) = config.localhost_answer(&question.name, question.q_type) {
+        authoritative = true;
+        res_packet.answers = local.answers;
+        res_packet.authority = local.authority;
+        res_packet.set_question(question);
+    } else if config.is_blocked(&question.name) {
+        println!("Blocked question for {}", question.name);
+        match config.sinkhole {
+            Some(addr) if question.q_type == QueryType::A => {
+                res_packet.answers.push(DNSRecord::new_a(
+                    &question.name,
+                    addr,
+                    config.local_answer_ttl,
+                ));
+            }
+            _ => res_packet.header.res_code = config.blocked_rcode,
+        }
+        if client_edns {
+            res_packet.ede = Some((EDE_BLOCKED, "Blocked".to_owned()));
+        }
+        res_packet.set_question(question);
+    } else if config.disable_ipv6 && question.q_type == QueryType::AAAA {
+        // NODATA rather than addresses this host has no route to
+        println!("IPv6 disabled, answering NODATA for {}", question.name);
+        res_packet.authority.push(config.local_soa(&question.name));
+        res_packet.set_question(question);
+    } else if let Some(local) = config.authority.lookup(&question.name, question.q_type) {
+        authoritative = true;
+        res_packet.set_question(question);
+        res_packet.answers = local.answers;
+    } else {
+        match resolve_with_stats(&question, protocol, &mut cache, config).await {
+            Ok((result, stats)) => {
+                println!("Resolved {} with {:?}", question.name, stats);
+                config.metrics.timing("resolve", stats.elapsed);
+                if let Some(server) = config.mirror_server {
+                    mirror_query(
+                        question.clone(),
+                        result.clone(),
+                        server,
+                        config.query_options.clone(),
+                    );
+                }
+                let redirect = if result.header.res_code == RCode::NXDOMAIN {
+                    config.nxdomain_redirect_record(&question.name, question.q_type)
+                } else {
+                    None
+                };
+                res_packet.set_question(question);
+                res_packet.header.res_code = result.header.res_code;
 
-            if let Some(new_ns) = recursive_response.get_random_a() {
-                ns = new_ns;
-            } else {
-                return Ok(response);
+                for rec in result.answers {
+                    println!("Answer: {}", rec);
+                    res_packet.answers.push(rec);
+                }
+                for rec in result.authority {
+                    println!("Authority: {}", rec);
+                    res_packet.authority.push(rec);
+                }
+                for rec in result.addtional {
+                    println!("Resource: {}", rec);
+                    res_packet.addtional.push(rec);
+                }
+                if let Some(record) = redirect {
+                    println!("Redirecting NXDOMAIN to {:?}", record);
+                    res_packet.header.res_code = RCode::NOERROR;
+                    // The negative answer's SOA no longer applies
+                    res_packet.authority.clear();
+                    res_packet.answers.push(record);
+                }
+            }
+            Err(err) => {
+                res_packet.header.res_code = RCode::SERVFAIL;
+                // Shedding load says nothing about the reachability of the authorities
+                if client_edns && err.downcast_ref::<Overloaded>().is_none() {
+                    res_packet.ede = Some((
+                        EDE_NO_REACHABLE_AUTHORITY,
+                        "No reachable authority".to_owned(),
+                    ));
+                }
             }
         }
-    })
+    }
+    (res_packet, authoritative)
 }
 
-#[derive(Debug, Copy, Clone)]
-enum ReqProtocol {
-    UDP,
-    TCP,
-}
+const DNS_MESSAGE: &str = "application/dns-message";
+
+// Largest DNS message, anything longer can't be a query (RFC 8484 section 6)
+const MAX_DOH_MESSAGE: usize = 65535;
 
 #[derive(Debug)]
 struct DNSUdpServer {
     tokio_socket: UdpSocket,
     std_socket: net::UdpSocket,
     cache: DNSCache,
+    config: Arc<ServerConfig>,
 }
 
 impl DNSUdpServer {
-    async fn new(addr: (&str, u16), cache: DNSCache) -> Result<DNSUdpServer> {
-        let std_socket = net::UdpSocket::bind(addr)?;
+    async fn new(
+        addr: (&str, u16),
+        cache: DNSCache,
+        config: Arc<ServerConfig>,
+    ) -> Result<DNSUdpServer> {
+        let addr = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| eyre!("Failed to resolve UDP server address"))?;
+        let domain = if addr.is_ipv4() { Domain::ipv4() } else { Domain::ipv6() };
+        let socket = Socket::new(domain, Type::dgram(), Some(Protocol::udp()))?;
+        if addr.is_ipv6() {
+            // Leave IPv4 to the 0.0.0.0 socket instead of getting mapped addresses here
+            socket.set_only_v6(true)?;
+        }
+        socket.bind(&addr.into())?;
+        let std_socket = socket.into_udp_socket();
         let tokio_socket = UdpSocket::from_std(std_socket.try_clone()?)?;
         Ok(DNSUdpServer {
             tokio_socket,
             std_socket,
-            cache
+            cache,
+            config,
         })
     }
 
-    async fn run_server<'a>(&'a mut self) -> Result<()> {
+    async fn run_server(&mut self) -> Result<()> {
         loop {
             let mut req_buffer = ArrayBuffer::new();
             // let socket_clone = self.socket.clone();
-            let src = match self.tokio_socket.recv_from(&mut req_buffer.buf).await {
+            let src = match req_buffer.recv_from(&mut self.tokio_socket).await {
                 Ok((_, src)) => src,
                 Err(e) => {
                     println!("Failed to read from UDP Socket: {}", e);
@@ -141,9 +305,16 @@ impl DNSUdpServer {
             };
             let std_socket_clone = self.std_socket.try_clone()?;
             let cache_clone = self.cache.clone();
+            let config_clone = self.config.clone();
             tokio::spawn(async move {
-                if let Err(err) =
-                    DNSUdpServer::handle_request(std_socket_clone, req_buffer, src, cache_clone).await
+                if let Err(err) = DNSUdpServer::handle_request(
+                    std_socket_clone,
+                    req_buffer,
+                    src,
+                    cache_clone,
+                    config_clone,
+                )
+                .await
                 {
                     println!("Failed to handle request from src {} : {}", src, err);
                 }
@@ -155,49 +326,68 @@ impl DNSUdpServer {
         socket: net::UdpSocket,
         mut req_buffer: ArrayBuffer,
         src: SocketAddr,
-        mut cache: DNSCache
+        cache: DNSCache,
+        config: Arc<ServerConfig>,
     ) -> Result<()> {
-        let mut request_packet = DNSPacket::from_buffer(&mut req_buffer)?;
-
-        let mut res_packet = DNSPacket::new();
-
-        res_packet.header.id = request_packet.header.id;
-        res_packet.header.recur_desired = request_packet.header.recur_desired;
-        res_packet.header.recur_available = true;
-        res_packet.header.query_response = true;
-        if let Some(question) = request_packet.questions.pop() {
-            println!("Recieved Question: {:?}", question);
-
-            if let Ok(result) =
-                recursive_lookup(&question.name, question.q_type, ReqProtocol::UDP, &mut cache).await
-            {
-                res_packet.questions.push(question);
-                res_packet.header.res_code = result.header.res_code;
-
-                for rec in result.answers {
-                    println!("Answer: {:?}", rec);
-                    res_packet.answers.push(rec);
-                }
-                for rec in result.authority {
-                    println!("Authority: {:?}", rec);
-                    res_packet.authority.push(rec);
+        let mut max_size = MIN_UDP_PAYLOAD;
+        let mut dedup_claim = None;
+        let mut res_packet = match DNSPacket::from_buffer(&mut req_buffer) {
+            Ok(request_packet) => {
+                if let Some(dedup) = &config.response_dedup {
+                    match dedup.claim(ResponseDedup::key(src, &request_packet)) {
+                        Dedup::Claimed(claim) => dedup_claim = Some(claim),
+                        // A retransmission, the response to the first copy answers it
+                        Dedup::InFlight => {
+                            config.metrics.incr("dedup_hits");
+                            return Ok(());
+                        }
+                        // A retransmission, the client gets the response sent a moment ago
+                        Dedup::Sent(res_bytes) => {
+                            config.metrics.incr("dedup_hits");
+                            return DNSUdpServer::send_response(socket, res_bytes, src).await;
+                        }
+                    }
                 }
-                for rec in result.addtional {
-                    println!("Resource: {:?}", rec);
-                    res_packet.addtional.push(rec);
+                // EDNS clients say how large a response they can take
+                if let Some(size) = request_packet.udp_payload_size() {
+                    max_size = size.clamp(MIN_UDP_PAYLOAD, MAX_UDP_PAYLOAD);
                 }
-            } else {
-                res_packet.header.res_code = RCode::SERVFAIL;
+                build_response(request_packet, ReqProtocol::UDP, cache, &config).await
             }
-        } else {
-            res_packet.header.res_code = RCode::FORMERR;
+            Err(err) => {
+                println!("Failed to parse request from {}: {}", src, err);
+                // Anything with a complete header still gets an answer
+                req_buffer.seek(0)?;
+                let mut res_packet = DNSPacket::new();
+                res_packet.header.read(&mut req_buffer)?;
+                res_packet.header.res_code = RCode::FORMERR;
+                let checking_disabled = res_packet.header.checking_disabled;
+                res_packet.sanitize(&ResponseContext {
+                    edns: false,
+                    authoritative: false,
+                    recursion_available: true,
+                    dnssec_ok: false,
+                    checking_disabled,
+                });
+                res_packet
+            }
+        };
+
+        let mut res_bytes = res_packet.to_bytes()?;
+        if res_bytes.len() > max_size as usize {
+            // Doesn't fit, the client has to retry over TCP
+            res_packet.truncate();
+            res_bytes = res_packet.to_bytes()?;
+        }
+        if let Some(claim) = dedup_claim {
+            claim.complete(res_bytes.clone());
         }
+        DNSUdpServer::send_response(socket, res_bytes, src).await
+    }
 
-        let mut res_buffer = ArrayBuffer::new();
-        res_packet.write(&mut res_buffer)?;
-        let len = res_buffer.pos();
+    async fn send_response(socket: net::UdpSocket, res_bytes: Vec<u8>, src: SocketAddr) -> Result<()> {
         tokio::task::spawn_blocking(move || {
-            if let Err(e) = socket.send_to(&res_buffer.buf[0..len], src) {
+            if let Err(e) = socket.send_to(&res_bytes, src) {
                 println!("Failed to send response to {} : {}", src, e);
             }
         })
@@ -209,98 +399,642 @@ impl DNSUdpServer {
 struct DNSTcpServer {
     listener: TcpListener,
     cache: DNSCache,
+    config: Arc<ServerConfig>,
 }
 
 impl DNSTcpServer {
-    async fn new(addr: (&str, u16), cache: DNSCache) -> Result<DNSTcpServer> {
+    async fn new(
+        addr: (&str, u16),
+        cache: DNSCache,
+        config: Arc<ServerConfig>,
+    ) -> Result<DNSTcpServer> {
+        // Bound through socket2 since neither std nor tokio let us pick the accept backlog
+        let addr = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| eyre!("Failed to resolve TCP server address"))?;
+        let domain = if addr.is_ipv4() { Domain::ipv4() } else { Domain::ipv6() };
+        let socket = Socket::new(domain, Type::stream(), Some(Protocol::tcp()))?;
+        socket.set_reuse_address(true)?;
+        if addr.is_ipv6() {
+            socket.set_only_v6(true)?;
+        }
+        socket.bind(&addr.into())?;
+        socket.listen(config.tcp_backlog)?;
         Ok(DNSTcpServer {
-            listener: TcpListener::bind(addr).await?,
+            listener: TcpListener::from_std(socket.into_tcp_listener())?,
             cache,
+            config,
         })
     }
 
     async fn run_server(&mut self) -> Result<()> {
         loop {
-            let (mut socket, _) = self.listener.accept().await?;
+            let (mut socket, src) = self.listener.accept().await?;
+            DNSTcpServer::configure_connection(&socket, &self.config);
             let cache_clone = self.cache.clone();
+            let config_clone = self.config.clone();
             tokio::spawn(async move {
-                if let Err(err) = DNSTcpServer::handle_connection(&mut socket, cache_clone).await {
-                    eprintln!(
-                        "Failed to handle request from src {} : {}",
-                        socket.peer_addr().unwrap(),
-                        err
-                    );
+                if let Err(err) =
+                    DNSTcpServer::handle_connection(&mut socket, cache_clone, config_clone).await
+                {
+                    // peer_addr() fails once the peer is gone, so the accepted address is used
+                    eprintln!("Failed to handle request from src {} : {}", src, err);
                 }
             });
         }
     }
 
-    async fn handle_connection(socket: &mut TcpStream, mut cache: DNSCache) -> Result<()> {
+    fn configure_connection(socket: &TcpStream, config: &ServerConfig) {
+        if let Err(err) = socket.set_nodelay(config.tcp_nodelay) {
+            eprintln!("Failed to set TCP_NODELAY on accepted socket: {}", err);
+        }
+    }
+
+    async fn handle_connection(
+        socket: &mut TcpStream,
+        cache: DNSCache,
+        config: Arc<ServerConfig>,
+    ) -> Result<()> {
         let mut req_buffer = VecBuffer::from_socket(socket).await?;
 
-        let mut request_packet = DNSPacket::from_buffer(&mut req_buffer)?;
+        let request_packet = DNSPacket::from_buffer(&mut req_buffer)?;
+        let mut res_packet = build_response(request_packet, ReqProtocol::TCP, cache, &config).await;
 
-        let mut res_packet = DNSPacket::new();
+        let mut res_buffer = VecBuffer::new();
+        res_packet.write(&mut res_buffer)?;
+        res_buffer.to_socket(socket).await?;
+        Ok(())
+    }
+}
 
-        res_packet.header.id = request_packet.header.id;
-        res_packet.header.recur_desired = request_packet.header.recur_desired;
-        res_packet.header.recur_available = true;
-        res_packet.header.query_response = true;
-        if let Some(question) = request_packet.questions.pop() {
-            println!("Recieved Question: {:?}", question);
+// DNS over HTTPS (RFC 8484) on /dns-query, as POST with an application/dns-message
+// body or GET with the base64url encoded message in `dns`. Speaks plain HTTP,
+// TLS is left to a reverse proxy in front of it.
+struct DNSHttpServer {
+    listener: net::TcpListener,
+    cache: DNSCache,
+    config: Arc<ServerConfig>,
+}
 
-            if let Ok(result) =
-                recursive_lookup(&question.name, question.q_type, ReqProtocol::TCP, &mut cache).await
-            {
-                res_packet.questions.push(question);
-                res_packet.header.res_code = result.header.res_code;
+impl DNSHttpServer {
+    fn new(
+        addr: (&str, u16),
+        cache: DNSCache,
+        config: Arc<ServerConfig>,
+    ) -> Result<DNSHttpServer> {
+        let addr = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| eyre!("Failed to resolve HTTP server address"))?;
+        let domain = if addr.is_ipv4() { Domain::ipv4() } else { Domain::ipv6() };
+        let socket = Socket::new(domain, Type::stream(), Some(Protocol::tcp()))?;
+        socket.set_reuse_address(true)?;
+        if addr.is_ipv6() {
+            socket.set_only_v6(true)?;
+        }
+        socket.bind(&addr.into())?;
+        socket.listen(config.tcp_backlog)?;
+        Ok(DNSHttpServer {
+            listener: socket.into_tcp_listener(),
+            cache,
+            config,
+        })
+    }
 
-                for rec in result.answers {
-                    println!("Answer: {:?}", rec);
-                    res_packet.answers.push(rec);
+    async fn run_server(self) -> Result<()> {
+        let cache = self.cache;
+        let config = self.config;
+        let make_service = make_service_fn(move |_| {
+            let cache = cache.clone();
+            let config = config.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    DNSHttpServer::handle_request(request, cache.clone(), config.clone())
+                }))
+            }
+        });
+        Server::from_tcp(self.listener)?.serve(make_service).await?;
+        Ok(())
+    }
+
+    fn error_response(status: StatusCode) -> Response<Body> {
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = status;
+        response
+    }
+
+    // Reads a POST body of at most MAX_DOH_MESSAGE bytes, refusing larger ones
+    // from their Content-Length or, without one, once the stream passes the limit
+    async fn read_body(request: Request<Body>) -> std::result::Result<Vec<u8>, StatusCode> {
+        let content_length = request
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok());
+        if content_length.is_some_and(|length| length > MAX_DOH_MESSAGE as u64) {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        let mut body = request.into_body();
+        let mut message = Vec::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.map_err(|_| StatusCode::BAD_REQUEST)?;
+            if message.len() + chunk.len() > MAX_DOH_MESSAGE {
+                return Err(StatusCode::PAYLOAD_TOO_LARGE);
+            }
+            message.extend_from_slice(&chunk);
+        }
+        Ok(message)
+    }
+
+    async fn handle_request(
+        request: Request<Body>,
+        cache: DNSCache,
+        config: Arc<ServerConfig>,
+    ) -> std::result::Result<Response<Body>, Infallible> {
+        if request.uri().path() != "/dns-query" {
+            return Ok(DNSHttpServer::error_response(StatusCode::NOT_FOUND));
+        }
+        let message = match *request.method() {
+            Method::POST => {
+                let content_type = request.headers().get(CONTENT_TYPE);
+                if content_type.map(|value| value.as_bytes()) != Some(DNS_MESSAGE.as_bytes()) {
+                    return Ok(DNSHttpServer::error_response(StatusCode::UNSUPPORTED_MEDIA_TYPE));
                 }
-                for rec in result.authority {
-                    println!("Authority: {:?}", rec);
-                    res_packet.authority.push(rec);
+                match DNSHttpServer::read_body(request).await {
+                    Ok(message) => message,
+                    Err(status) => return Ok(DNSHttpServer::error_response(status)),
                 }
-                for rec in result.addtional {
-                    println!("Resource: {:?}", rec);
-                    res_packet.addtional.push(rec);
+            }
+            Method::GET => {
+                let encoded = request.uri().query().and_then(|query| {
+                    query.split('&').find_map(|pair| pair.strip_prefix("dns="))
+                });
+                match encoded.map(|encoded| base64::decode_config(encoded, base64::URL_SAFE_NO_PAD)) {
+                    Some(Ok(message)) if message.len() <= MAX_DOH_MESSAGE => message,
+                    Some(Ok(_)) => {
+                        return Ok(DNSHttpServer::error_response(StatusCode::PAYLOAD_TOO_LARGE))
+                    }
+                    _ => return Ok(DNSHttpServer::error_response(StatusCode::BAD_REQUEST)),
                 }
-            } else {
-                res_packet.header.res_code = RCode::SERVFAIL;
             }
-        } else {
-            res_packet.header.res_code = RCode::FORMERR;
-        }
+            _ => return Ok(DNSHttpServer::error_response(StatusCode::METHOD_NOT_ALLOWED)),
+        };
 
-        let mut res_buffer = VecBuffer::new();
-        res_packet.write(&mut res_buffer)?;
-        res_buffer.to_socket(socket).await?;
-        Ok(())
+        let request_packet = match DNSPacket::from_bytes(&message) {
+            Ok(request_packet) => request_packet,
+            Err(err) => {
+                println!("Failed to parse DoH request: {}", err);
+                return Ok(DNSHttpServer::error_response(StatusCode::BAD_REQUEST));
+            }
+        };
+        // Responses of any size fit in an HTTP body, so upstreams are asked like for TCP clients
+        let mut res_packet = build_response(request_packet, ReqProtocol::TCP, cache, &config).await;
+        // Cached by HTTP caches no longer than its shortest TTL (RFC 8484 section 5.1)
+        let max_age = res_packet
+            .answers
+            .iter()
+            .chain(res_packet.authority.iter())
+            .map(|record| record.get_ttl())
+            .min()
+            .unwrap_or(0);
+        let res_bytes = match res_packet.to_bytes() {
+            Ok(res_bytes) => res_bytes,
+            Err(err) => {
+                println!("Failed to write DoH response: {}", err);
+                return Ok(DNSHttpServer::error_response(StatusCode::INTERNAL_SERVER_ERROR));
+            }
+        };
+        let mut response = Response::new(Body::from(res_bytes));
+        response
+            .headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static(DNS_MESSAGE));
+        response.headers_mut().insert(
+            CACHE_CONTROL,
+            HeaderValue::from_str(&format!("max-age={}", max_age)).unwrap(),
+        );
+        Ok(response)
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let cache = DNSCache {
-        map: Arc::new(Mutex::new(HashMap::new()))
-    };
-    let tcp_cache = cache.clone();
-    let mut udp_server = DNSUdpServer::new(("0.0.0.0", 2053), cache).await?;
+// Starts the UDP, TCP and DoH servers for one wildcard address. Each answers on the
+// socket a request arrived on, so responses keep the request's address family.
+async fn spawn_servers(
+    host: &str,
+    cache: DNSCache,
+    config: Arc<ServerConfig>,
+) -> Result<Vec<JoinHandle<()>>> {
+    let mut udp_server = DNSUdpServer::new((host, 2053), cache.clone(), config.clone()).await?;
     let udp_server_handle = tokio::spawn(async move {
         if let Err(err) = udp_server.run_server().await {
             eprintln!("Failed to start UDP server: {}", err);
         }
     });
-    let mut tcp_server = DNSTcpServer::new(("0.0.0.0", 2054), tcp_cache).await?;
+    let mut tcp_server = DNSTcpServer::new((host, 2054), cache.clone(), config.clone()).await?;
     let tcp_server_handle = tokio::spawn(async move {
         if let Err(err) = tcp_server.run_server().await {
             eprintln!("Failed to start TCP server: {}", err);
         }
     });
-    let (first, second) = tokio::join!(udp_server_handle, tcp_server_handle);
-    first?;
-    second?;
+    let mut handles = vec![udp_server_handle, tcp_server_handle];
+    if config.serve_doh {
+        let http_server = DNSHttpServer::new((host, 2080), cache, config)?;
+        handles.push(tokio::spawn(async move {
+            if let Err(err) = http_server.run_server().await {
+                eprintln!("Failed to start DoH server: {}", err);
+            }
+        }));
+    }
+    Ok(handles)
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cache = DNSCache::new();
+    let mut config = ServerConfig::default();
+    if let Some(path) = std::env::args().nth(1) {
+        config.authority = authority::load_records_json(&path)?;
+        println!("Loaded local records from {}", path);
+    }
+    let config = Arc::new(config);
+    if let Some(server) = config.statsd_server {
+        let emitter = run_statsd_emitter(
+            config.metrics.clone(),
+            server,
+            config.statsd_prefix.clone(),
+            config.statsd_interval,
+        );
+        tokio::spawn(async move {
+            if let Err(err) = emitter.await {
+                eprintln!("Stopped pushing metrics to StatsD: {}", err);
+            }
+        });
+    }
+    let mut handles = spawn_servers("0.0.0.0", cache.clone(), config.clone()).await?;
+    // A server told to stay off IPv6 doesn't listen on it either
+    if config.listen_ipv6 && !config.disable_ipv6 {
+        // Not every host has IPv6, serving IPv4 only is still useful there
+        match spawn_servers("::", cache, config.clone()).await {
+            Ok(ipv6_handles) => handles.extend(ipv6_handles),
+            Err(err) => eprintln!("Failed to start IPv6 servers: {}", err),
+        }
+    }
+    for result in join_all(handles).await {
+        result?;
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(name: &str, q_type: QueryType) -> DNSPacket {
+        let mut packet = DNSPacket::new();
+        packet.header.id = 4242;
+        packet.header.recur_desired = true;
+        packet.set_question(DNSQuestion::new(name.to_owned(), q_type));
+        packet
+    }
+
+    // No upstream can be queried within a budget of zero queries, so whatever
+    // isn't a SERVFAIL was answered by the server itself
+    fn offline_config() -> ServerConfig {
+        ServerConfig {
+            query_budget: 0,
+            ..ServerConfig::default()
+        }
+    }
+
+    async fn answer(request: DNSPacket, config: &ServerConfig) -> DNSPacket {
+        build_response(request, ReqProtocol::UDP, DNSCache::new(), config).await
+    }
+
+    #[tokio::test]
+    async fn mirror_gets_the_question_and_only_answer_differences_count() {
+        let mut mirror = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut primary = DNSPacket::new();
+        primary
+            .answers
+            .push(DNSRecord::new_a("www.example", net::Ipv4Addr::new(192, 0, 2, 1), 300));
+        mirror_query(
+            DNSQuestion::new("www.example".to_owned(), QueryType::A),
+            primary.clone(),
+            mirror.local_addr().unwrap(),
+            QueryOptions::default(),
+        );
+        let mut req_buffer = ArrayBuffer::new();
+        req_buffer.recv_from(&mut mirror).await.unwrap();
+        let mirrored = DNSPacket::from_buffer(&mut req_buffer).unwrap();
+        assert_eq!(mirrored.questions[0].name, "www.example");
+        assert_eq!(mirrored.questions[0].q_type, QueryType::A);
+
+        let mut secondary = primary.clone();
+        secondary
+            .authority
+            .push(DNSRecord::new_a("ns.example", net::Ipv4Addr::new(192, 0, 2, 53), 300));
+        assert!(mirror_divergence(&primary, &secondary).is_empty());
+        secondary.answers[0] =
+            DNSRecord::new_a("www.example", net::Ipv4Addr::new(192, 0, 2, 2), 300);
+        assert_eq!(mirror_divergence(&primary, &secondary).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn accepted_connections_get_the_configured_nodelay() {
+        for &tcp_nodelay in [true, false].iter() {
+            let config = Arc::new(ServerConfig {
+                tcp_nodelay,
+                ..ServerConfig::default()
+            });
+            let mut server = DNSTcpServer::new(("127.0.0.1", 0), DNSCache::new(), config.clone())
+                .await
+                .unwrap();
+            let addr = server.listener.local_addr().unwrap();
+            let _client = net::TcpStream::connect(addr).unwrap();
+            let (socket, _) = server.listener.accept().await.unwrap();
+            DNSTcpServer::configure_connection(&socket, &config);
+            assert_eq!(socket.nodelay().unwrap(), tcp_nodelay);
+        }
+    }
+
+    #[tokio::test]
+    async fn blocked_suffixes_are_answered_without_recursion() {
+        let config = ServerConfig {
+            blocked_suffixes: vec!["internal".to_owned()],
+            ..offline_config()
+        };
+        let response = answer(request("svc.internal", QueryType::A), &config).await;
+        assert_eq!(response.header.res_code, RCode::NXDOMAIN);
+        assert_eq!(response.header.id, 4242);
+        assert_eq!(response.questions[0].name, "svc.internal");
+
+        let response = answer(request("svc.internal.example", QueryType::A), &config).await;
+        assert_eq!(response.header.res_code, RCode::SERVFAIL);
+    }
+
+    #[tokio::test]
+    async fn sinkholed_answers_carry_the_local_answer_ttl() {
+        let config = ServerConfig {
+            blocked_suffixes: vec!["ads.example".to_owned()],
+            sinkhole: Some(net::Ipv4Addr::new(192, 0, 2, 99)),
+            local_answer_ttl: 30,
+            ..offline_config()
+        };
+        let response = answer(request("tracker.ads.example", QueryType::A), &config).await;
+        assert_eq!(response.header.res_code, RCode::NOERROR);
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.answers[0].get_ttl(), 30);
+        assert_eq!(
+            response.get_random_a(),
+            Some(net::Ipv4Addr::new(192, 0, 2, 99))
+        );
+    }
+
+    #[tokio::test]
+    async fn questions_over_the_name_limit_are_refused() {
+        let config = ServerConfig {
+            max_qname_len: 21,
+            ..offline_config()
+        };
+        // (1 + 10) + (1 + 7) + (1 + 1) + 1 = 22 octets on the wire
+        let response = answer(request("abcdefghij.example.x", QueryType::A), &config).await;
+        assert_eq!(response.header.res_code, RCode::REFUSED);
+        assert_eq!(response.questions.len(), 1);
+        // Right at the limit the question is resolved as usual
+        let response = answer(request("abcdefghi.example.x", QueryType::A), &config).await;
+        assert_eq!(response.header.res_code, RCode::SERVFAIL);
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    async fn nxdomain_is_redirected_to_the_landing_address() {
+        let root = testing::MockNameServer::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let mut nxdomain = DNSPacket::new();
+        nxdomain.header.auth_answer = true;
+        nxdomain.header.res_code = RCode::NXDOMAIN;
+        root.answer("typo.example", QueryType::A, nxdomain.clone());
+        root.answer("typo.example", QueryType::MX, nxdomain);
+        let landing = net::Ipv4Addr::new(192, 0, 2, 80);
+        let config = ServerConfig {
+            root_server: net::Ipv4Addr::LOCALHOST,
+            nameserver_port: root.addr().port(),
+            nxdomain_redirect: Some(landing.into()),
+            ..ServerConfig::default()
+        };
+
+        let response = answer(request("typo.example", QueryType::A), &config).await;
+        assert_eq!(response.header.res_code, RCode::NOERROR);
+        assert_eq!(
+            response.answers,
+            vec![DNSRecord::new_a("typo.example", landing, config.local_answer_ttl)]
+        );
+        // Only questions the landing address can answer are redirected
+        let response = answer(request("typo.example", QueryType::MX), &config).await;
+        assert_eq!(response.header.res_code, RCode::NXDOMAIN);
+    }
+
+    #[tokio::test]
+    async fn localhost_is_answered_without_recursion() {
+        let response = answer(request("localhost", QueryType::A), &offline_config()).await;
+        assert_eq!(response.header.res_code, RCode::NOERROR);
+        assert!(response.header.auth_answer);
+        assert_eq!(response.get_random_a(), Some(net::Ipv4Addr::LOCALHOST));
+        let response = answer(request("db.localhost.", QueryType::AAAA), &offline_config()).await;
+        assert_eq!(response.get_random_aaaa(), Some(net::Ipv6Addr::LOCALHOST));
+        // Other types exist as empty answers
+        let response = answer(request("localhost", QueryType::MX), &offline_config()).await;
+        assert_eq!(response.header.res_code, RCode::NOERROR);
+        assert!(response.answers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn answers_queries_over_ipv6_loopback() {
+        let config = Arc::new(offline_config());
+        let mut server = DNSUdpServer::new(("::1", 0), DNSCache::new(), config).await.unwrap();
+        let addr = server.std_socket.local_addr().unwrap();
+        tokio::spawn(async move { server.run_server().await });
+
+        let mut client = UdpSocket::bind("[::1]:0").await.unwrap();
+        let mut query = request("localhost", QueryType::A);
+        client.send_to(&query.to_bytes().unwrap(), addr).await.unwrap();
+        let mut res_bytes = [0; 512];
+        let (len, src) = client.recv_from(&mut res_bytes).await.unwrap();
+        // Back from the very socket the query went to
+        assert_eq!(src, addr);
+        let response = DNSPacket::from_bytes(&res_bytes[..len]).unwrap();
+        assert_eq!(response.header.id, 4242);
+        assert_eq!(response.get_random_a(), Some(net::Ipv4Addr::LOCALHOST));
+    }
+
+    #[tokio::test]
+    async fn header_only_datagram_gets_formerr() {
+        let server = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        // A whole question left over from an earlier request sits after the header
+        let mut stale = request("www.example", QueryType::A);
+        let stale = stale.to_bytes().unwrap();
+        let mut req_buffer = ArrayBuffer::new();
+        req_buffer.buf[..stale.len()].copy_from_slice(&stale);
+        req_buffer.filled = 12;
+        DNSUdpServer::handle_request(
+            server.try_clone().unwrap(),
+            req_buffer,
+            client.local_addr().unwrap(),
+            DNSCache::new(),
+            Arc::new(offline_config()),
+        )
+        .await
+        .unwrap();
+
+        let mut res_bytes = [0; 512];
+        let len = client.recv(&mut res_bytes).unwrap();
+        let response = DNSPacket::from_bytes(&res_bytes[..len]).unwrap();
+        assert_eq!(response.header.id, 4242);
+        assert_eq!(response.header.res_code, RCode::FORMERR);
+    }
+
+    #[tokio::test]
+    async fn types_outside_the_allowlist_are_refused() {
+        let config = ServerConfig {
+            allowed_types: Some(vec![QueryType::A].into_iter().collect()),
+            ..offline_config()
+        };
+        let response = answer(request("www.example", QueryType::TXT), &config).await;
+        assert_eq!(response.header.res_code, RCode::REFUSED);
+        let response = answer(request("www.example", QueryType::A), &config).await;
+        assert_eq!(response.header.res_code, RCode::SERVFAIL);
+    }
+
+    #[tokio::test]
+    async fn own_addresses_get_the_local_ptr() {
+        let mut config = offline_config();
+        config
+            .local_ptr
+            .insert(net::Ipv4Addr::new(127, 0, 0, 1).into(), "resolver.lan".to_owned());
+        let response = answer(request("1.0.0.127.in-addr.arpa", QueryType::PTR), &config).await;
+        assert_eq!(response.header.res_code, RCode::NOERROR);
+        assert!(response.header.auth_answer);
+        assert_eq!(response.get_ptr(), Some("resolver.lan"));
+        // Loopback addresses without an entry keep the built-in answer
+        let response = answer(request("2.0.0.127.in-addr.arpa", QueryType::PTR), &config).await;
+        assert_eq!(response.get_ptr(), Some("localhost"));
+    }
+
+    #[tokio::test]
+    async fn aaaa_with_ipv6_disabled_is_nodata() {
+        let config = ServerConfig {
+            disable_ipv6: true,
+            ..offline_config()
+        };
+        let response = answer(request("www.example", QueryType::AAAA), &config).await;
+        assert_eq!(response.header.res_code, RCode::NOERROR);
+        assert!(response.answers.is_empty());
+        assert!(matches!(response.authority[..], [DNSRecord::SOA { .. }]));
+        // Other types are still resolved
+        let response = answer(request("www.example", QueryType::A), &config).await;
+        assert_eq!(response.header.res_code, RCode::SERVFAIL);
+    }
+
+    #[tokio::test]
+    async fn update_gets_notimp_with_its_opcode() {
+        let mut update = request("example.com", QueryType::SOA);
+        update.header.opcode = OpCode::UPDATE;
+        let response = answer(update, &offline_config()).await;
+        assert_eq!(response.header.res_code, RCode::NOTIMP);
+        assert_eq!(response.header.opcode, OpCode::UPDATE);
+        assert_eq!(response.header.id, 4242);
+        assert!(response.answers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn qclass_any_is_not_implemented() {
+        let mut query = request("www.example", QueryType::A);
+        query.questions[0].class = QueryClass::ANY;
+        let response = answer(query, &offline_config()).await;
+        assert_eq!(response.header.res_code, RCode::NOTIMP);
+        assert_eq!(response.questions[0].class, QueryClass::ANY);
+    }
+
+    #[tokio::test]
+    async fn unknown_edns_versions_get_badvers() {
+        let mut query = request("www.example", QueryType::A);
+        query.set_edns(1, 0);
+        let mut response = answer(query, &offline_config()).await;
+        // The upper rcode bits only survive the trip through the OPT record
+        let response = DNSPacket::from_bytes(&response.to_bytes().unwrap()).unwrap();
+        assert_eq!(response.header.res_code, RCode::BADVERS);
+        assert_eq!(response.edns_version(), Some(EDNS_VERSION));
+
+        let mut query = request("www.example", QueryType::A);
+        query.set_edns(EDNS_VERSION, 0);
+        let response = answer(query, &offline_config()).await;
+        assert_eq!(response.header.res_code, RCode::SERVFAIL);
+    }
+
+    #[tokio::test]
+    async fn blocked_answers_carry_the_blocked_extended_error() {
+        let config = ServerConfig {
+            blocked_suffixes: vec!["ads.example".to_owned()],
+            ..offline_config()
+        };
+        let mut edns_request = request("tracker.ads.example", QueryType::A);
+        edns_request.set_udp_payload_size(1232);
+        let mut response = answer(edns_request, &config).await;
+        let parsed = DNSPacket::from_bytes(&response.to_bytes().unwrap()).unwrap();
+        let mut expected = EDE_BLOCKED.to_be_bytes().to_vec();
+        expected.extend_from_slice(b"Blocked");
+        assert_eq!(parsed.edns_option(EDNS_OPTION_EDE), Some(&expected[..]));
+
+        // Without an OPT in the request there is nowhere to put it
+        let response = answer(request("tracker.ads.example", QueryType::A), &config).await;
+        assert!(response.get_opt().is_none());
+        assert_eq!(response.header.res_code, RCode::NXDOMAIN);
+    }
+
+    fn post(body: Body, content_length: Option<usize>) -> Request<Body> {
+        let mut builder = Request::post("/dns-query").header(CONTENT_TYPE, DNS_MESSAGE);
+        if let Some(length) = content_length {
+            builder = builder.header(CONTENT_LENGTH, length);
+        }
+        builder.body(body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn doh_bodies_are_limited_to_a_dns_message() {
+        let body = Body::from(vec![0; MAX_DOH_MESSAGE]);
+        assert_eq!(
+            DNSHttpServer::read_body(post(body, None)).await.unwrap().len(),
+            MAX_DOH_MESSAGE
+        );
+        // Refused from the header alone, however short the body really is
+        let request = post(Body::empty(), Some(MAX_DOH_MESSAGE + 1));
+        assert_eq!(
+            DNSHttpServer::read_body(request).await.err(),
+            Some(StatusCode::PAYLOAD_TOO_LARGE)
+        );
+        // Without a Content-Length the stream is cut off at the limit
+        let chunks: Vec<std::result::Result<_, Infallible>> =
+            vec![Ok(vec![0; 40000]), Ok(vec![0; 40000])];
+        let request = post(Body::wrap_stream(futures::stream::iter(chunks)), None);
+        assert_eq!(
+            DNSHttpServer::read_body(request).await.err(),
+            Some(StatusCode::PAYLOAD_TOO_LARGE)
+        );
+    }
+
+    #[tokio::test]
+    async fn oversized_doh_request_gets_413() {
+        let request = post(Body::from(vec![0; MAX_DOH_MESSAGE + 1]), None);
+        let response = DNSHttpServer::handle_request(
+            request,
+            DNSCache::new(),
+            Arc::new(ServerConfig::default()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(!ServerConfig::default().serve_doh);
+    }
+}
@@ -0,0 +1,173 @@
+use eyre::Result;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::interval;
+
+// Stays below the usual 1500 byte MTU once IP and UDP headers are added
+const MAX_STATSD_DATAGRAM: usize = 1432;
+
+#[derive(Debug, Default)]
+struct Registry {
+    counters: HashMap<String, u64>,
+    // Counter values as of the last push, StatsD expects increments
+    pushed: HashMap<String, u64>,
+    // Samples not pushed yet, in milliseconds. Only collected while an emitter
+    // drains them, nothing would bound them otherwise.
+    timings: HashMap<String, Vec<u64>>,
+    record_timings: bool,
+}
+
+// Counters and timings of the running server, shared by every request
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    registry: Arc<Mutex<Registry>>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    pub fn incr(&self, name: &str) {
+        *self
+            .registry
+            .lock()
+            .unwrap()
+            .counters
+            .entry(name.to_owned())
+            .or_default() += 1;
+    }
+
+    pub fn counter(&self, name: &str) -> u64 {
+        self.registry
+            .lock()
+            .unwrap()
+            .counters
+            .get(name)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    pub fn timing(&self, name: &str, elapsed: Duration) {
+        let mut registry = self.registry.lock().unwrap();
+        if !registry.record_timings {
+            return;
+        }
+        registry
+            .timings
+            .entry(name.to_owned())
+            .or_default()
+            .push(elapsed.as_millis() as u64);
+    }
+
+    // Called by the emitter that will push and clear the samples
+    fn record_timings(&self) {
+        self.registry.lock().unwrap().record_timings = true;
+    }
+
+    // Everything that changed since the previous call as StatsD lines, e.g.
+    // "diglett.queries:3|c" and "diglett.resolve:12|ms"
+    pub fn statsd_lines(&self, prefix: &str) -> Vec<String> {
+        let mut registry = self.registry.lock().unwrap();
+        let mut lines = Vec::new();
+        let counters = registry.counters.clone();
+        for (name, value) in counters {
+            let pushed = registry.pushed.insert(name.clone(), value).unwrap_or(0);
+            if value > pushed {
+                lines.push(format!("{}.{}:{}|c", prefix, name, value - pushed));
+            }
+        }
+        for (name, samples) in registry.timings.drain() {
+            for sample in samples {
+                lines.push(format!("{}.{}:{}|ms", prefix, name, sample));
+            }
+        }
+        lines
+    }
+}
+
+// Packs lines into as few datagrams as fit, StatsD takes newline separated metrics
+fn statsd_datagrams(lines: Vec<String>) -> Vec<String> {
+    let mut datagrams: Vec<String> = Vec::new();
+    for line in lines {
+        match datagrams.last_mut() {
+            Some(datagram) if datagram.len() + 1 + line.len() <= MAX_STATSD_DATAGRAM => {
+                datagram.push('\n');
+                datagram.push_str(&line);
+            }
+            _ => datagrams.push(line),
+        }
+    }
+    datagrams
+}
+
+// Pushes the metrics to a StatsD server every `period`. Failed sends are only
+// logged, the metrics of that period are lost but the next push goes ahead.
+pub async fn run_statsd_emitter(
+    metrics: Metrics,
+    server: SocketAddr,
+    prefix: String,
+    period: Duration,
+) -> Result<()> {
+    let bind_addr = if server.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let mut socket = UdpSocket::bind(bind_addr).await?;
+    metrics.record_timings();
+    let mut ticks = interval(period);
+    loop {
+        ticks.tick().await;
+        for datagram in statsd_datagrams(metrics.statsd_lines(&prefix)) {
+            if let Err(err) = socket.send_to(datagram.as_bytes(), server).await {
+                eprintln!("Failed to push metrics to StatsD at {}: {}", server, err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timings_are_only_kept_for_an_emitter() {
+        let metrics = Metrics::new();
+        metrics.timing("resolve", Duration::from_millis(12));
+        assert!(metrics.statsd_lines("diglett").is_empty());
+
+        metrics.record_timings();
+        metrics.timing("resolve", Duration::from_millis(12));
+        assert_eq!(
+            metrics.statsd_lines("diglett"),
+            vec!["diglett.resolve:12|ms"]
+        );
+        assert!(metrics.statsd_lines("diglett").is_empty());
+    }
+
+    #[test]
+    fn counters_are_pushed_as_increments() {
+        let metrics = Metrics::new();
+        metrics.incr("queries");
+        metrics.incr("queries");
+        assert_eq!(metrics.statsd_lines("diglett"), vec!["diglett.queries:2|c"]);
+        metrics.incr("queries");
+        assert_eq!(metrics.statsd_lines("diglett"), vec!["diglett.queries:1|c"]);
+        assert_eq!(metrics.counter("queries"), 3);
+    }
+
+    #[test]
+    fn lines_are_packed_into_datagrams_below_the_mtu() {
+        let lines = vec!["x".repeat(1000), "y".repeat(400), "z".repeat(100)];
+        let datagrams = statsd_datagrams(lines);
+        assert_eq!(datagrams.len(), 2);
+        assert_eq!(datagrams[0].len(), 1000 + 1 + 400);
+        assert!(datagrams
+            .iter()
+            .all(|datagram| datagram.len() <= MAX_STATSD_DATAGRAM));
+    }
+}
@@ -0,0 +1,237 @@
+use crate::{is_subdomain_of, normalize_name, DNSRecord};
+use sha1::{Digest, Sha1};
+
+// The only hash algorithm defined for NSEC3 (RFC 5155 section 11)
+const NSEC3_SHA1: u8 = 1;
+// Chains with more iterations are treated as insecure rather than hashed, so a
+// crafted record can't make us spend up to 65535 SHA-1 rounds per name
+// (RFC 9276 section 3.2)
+pub const NSEC3_MAX_ITERATIONS: u16 = 100;
+
+// Canonical wire format of a name: lowercased labels and the terminating null label
+pub(crate) fn canonical_wire_name(name: &str) -> Vec<u8> {
+    let name = normalize_name(name);
+    let mut wire = Vec::with_capacity(name.len() + 2);
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        wire.push(label.len() as u8);
+        wire.extend_from_slice(label.as_bytes());
+    }
+    wire.push(0);
+    wire
+}
+
+// IH(salt, x, 0) = H(x || salt), IH(salt, x, k) = H(IH(salt, x, k - 1) || salt)
+pub fn nsec3_hash(name: &str, salt: &[u8], iterations: u16) -> Vec<u8> {
+    let mut digest = Sha1::new()
+        .chain_update(canonical_wire_name(name))
+        .chain_update(salt)
+        .finalize();
+    for _ in 0..iterations {
+        digest = Sha1::new()
+            .chain_update(digest)
+            .chain_update(salt)
+            .finalize();
+    }
+    digest.to_vec()
+}
+
+// Owner names carry the hash as a base32hex label (RFC 4648 section 7), without padding
+fn base32hex_decode(label: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(label.len() * 5 / 8);
+    let mut bits: u32 = 0;
+    let mut bit_count = 0;
+    for c in label.bytes() {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'0'..=b'9' => c - b'0',
+            c @ b'A'..=b'V' => c - b'A' + 10,
+            _ => return None,
+        };
+        bits = (bits << 5) | value as u32;
+        bit_count += 5;
+        if bit_count >= 8 {
+            bit_count -= 8;
+            out.push((bits >> bit_count) as u8);
+            bits &= (1 << bit_count) - 1;
+        }
+    }
+    Some(out)
+}
+
+pub(crate) fn base32hex_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUV";
+    let mut out = String::with_capacity((data.len() * 8).div_ceil(5));
+    let mut bits: u32 = 0;
+    let mut bit_count = 0;
+    for byte in data {
+        bits = (bits << 8) | *byte as u32;
+        bit_count += 8;
+        while bit_count >= 5 {
+            bit_count -= 5;
+            out.push(ALPHABET[(bits >> bit_count) as usize & 0x1F] as char);
+        }
+        bits &= (1 << bit_count) - 1;
+    }
+    if bit_count > 0 {
+        out.push(ALPHABET[(bits << (5 - bit_count)) as usize & 0x1F] as char);
+    }
+    out
+}
+
+// Splits an NSEC3 owner name into its hashed label and the zone it belongs to
+fn owner_hash(record: &DNSRecord) -> Option<(Vec<u8>, String)> {
+    let owner = normalize_name(record.get_name());
+    let (label, zone) = owner.split_at(owner.find('.')?);
+    Some((base32hex_decode(label)?, zone[1..].to_string()))
+}
+
+// An NSEC3 record covers a hash that sorts strictly between its owner hash and
+// the next hashed owner. The last record in the chain wraps around to the first.
+pub fn nsec3_covers(record: &DNSRecord, hash: &[u8]) -> bool {
+    let next_hashed = match record {
+        DNSRecord::NSEC3 { next_hashed, .. } => next_hashed,
+        _ => return false,
+    };
+    let owner = match owner_hash(record) {
+        Some((owner, _)) => owner,
+        None => return false,
+    };
+    let hash = hash.to_vec();
+    if owner < *next_hashed {
+        owner < hash && hash < *next_hashed
+    } else {
+        owner < hash || hash < *next_hashed
+    }
+}
+
+fn nsec3_matches(record: &DNSRecord, hash: &[u8]) -> bool {
+    match owner_hash(record) {
+        Some((owner, _)) => owner == hash,
+        None => false,
+    }
+}
+
+// Closest encloser proof for NXDOMAIN (RFC 5155 section 8.4): some ancestor of the
+// qname must match an NSEC3 owner, the next closer name below it must be covered,
+// and so must the wildcard at the closest encloser. Returns the closest encloser,
+// None without a proof or for a chain with too many iterations to check.
+pub fn closest_encloser_proof(records: &[DNSRecord], qname: &str) -> Option<String> {
+    let nsec3s: Vec<&DNSRecord> = records
+        .iter()
+        .filter(|record| matches!(record, DNSRecord::NSEC3 { .. }))
+        .collect();
+    let (hash_algorithm, salt, iterations) = match nsec3s.first()? {
+        DNSRecord::NSEC3 {
+            hash_algorithm,
+            salt,
+            iterations,
+            ..
+        } => (*hash_algorithm, salt, *iterations),
+        _ => return None,
+    };
+    if hash_algorithm != NSEC3_SHA1 || iterations > NSEC3_MAX_ITERATIONS {
+        return None;
+    }
+    let (_, zone) = owner_hash(nsec3s[0])?;
+
+    let qname = normalize_name(qname);
+    if !is_subdomain_of(&qname, &zone) {
+        return None;
+    }
+    let hashed = |name: &str| nsec3_hash(name, salt, iterations);
+    let is_covered = |hash: &[u8]| nsec3s.iter().any(|record| nsec3_covers(record, hash));
+    let is_matched = |hash: &[u8]| nsec3s.iter().any(|record| nsec3_matches(record, hash));
+
+    let mut next_closer = qname.as_str();
+    while next_closer != zone {
+        let encloser = match next_closer.find('.') {
+            Some(dot) => &next_closer[dot + 1..],
+            None => "",
+        };
+        if is_matched(&hashed(encloser)) {
+            let wildcard = if encloser.is_empty() {
+                "*".to_string()
+            } else {
+                format!("*.{}", encloser)
+            };
+            if is_covered(&hashed(next_closer)) && is_covered(&hashed(&wildcard)) {
+                return Some(encloser.to_string());
+            }
+            return None;
+        }
+        next_closer = encloser;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::QueryType;
+
+    const SALT: [u8; 4] = [0xaa, 0xbb, 0xcc, 0xdd];
+
+    fn nsec3(owner: &str, next: &str, iterations: u16) -> DNSRecord {
+        DNSRecord::NSEC3 {
+            name: format!("{}.example", owner),
+            q_type: QueryType::NSEC3,
+            class: 1,
+            ttl: 3600,
+            len: 0,
+            hash_algorithm: NSEC3_SHA1,
+            flags: 0,
+            iterations,
+            salt: SALT.to_vec(),
+            next_hashed: base32hex_decode(next).unwrap(),
+            type_bitmaps: Vec::new(),
+        }
+    }
+
+    // The NXDOMAIN proof for a.c.x.w.example from RFC 5155 appendix B.1
+    fn nxdomain_proof(iterations: u16) -> Vec<DNSRecord> {
+        vec![
+            nsec3(
+                "0p9mhaveqvm6t7vbl5lop2u3t2rp3tom",
+                "2t7b4g4vsa5smi47k61mv5bv1a22bojr",
+                iterations,
+            ),
+            nsec3(
+                "b4um86eghhds6nea196smvmlo4ors995",
+                "gjeqe526plbf1g8mklp59enfd789njgi",
+                iterations,
+            ),
+            nsec3(
+                "35mthgpgcu1qg68fab165klnsnk3dpvl",
+                "b4um86eghhds6nea196smvmlo4ors995",
+                iterations,
+            ),
+        ]
+    }
+
+    #[test]
+    fn hashes_names_like_rfc_5155_appendix_a() {
+        let hash = |name| base32hex_encode(&nsec3_hash(name, &SALT, 12)).to_lowercase();
+        assert_eq!(hash("example"), "0p9mhaveqvm6t7vbl5lop2u3t2rp3tom");
+        assert_eq!(hash("a.example"), "35mthgpgcu1qg68fab165klnsnk3dpvl");
+        assert_eq!(hash("*.w.example"), "r53bq7cc2uvmubfu5ocmm6pers9tk9en");
+    }
+
+    #[test]
+    fn base32hex_round_trips() {
+        let data = [0xff, 0x01, 0x02, 0x03, 0x04];
+        assert_eq!(base32hex_decode(&base32hex_encode(&data)).unwrap(), data);
+    }
+
+    #[test]
+    fn proves_the_closest_encloser() {
+        assert_eq!(
+            closest_encloser_proof(&nxdomain_proof(12), "a.c.x.w.example"),
+            Some("x.w.example".to_owned())
+        );
+    }
+
+    #[test]
+    fn refuses_chains_with_too_many_iterations() {
+        let records = nxdomain_proof(NSEC3_MAX_ITERATIONS + 1);
+        assert_eq!(closest_encloser_proof(&records, "a.c.x.w.example"), None);
+    }
+}
@@ -0,0 +1,1465 @@
+use crate::buffer::{ArrayBuffer, PacketBufferTrait, VecBuffer};
+use crate::cache::DNSCache;
+use crate::config::ServerConfig;
+use crate::{
+    normalize_name, serial_newer, DNSPacket, DNSQuestion, DNSRecord, OpCode, QueryType, RCode,
+    MIN_UDP_PAYLOAD,
+};
+use eyre::{eyre, Result};
+use futures::future::{join_all, BoxFuture};
+use rand::Rng;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::Semaphore;
+use tokio::time::{delay_for, timeout};
+use tokio_rustls::rustls::ClientConfig;
+use tokio_rustls::webpki::DNSNameRef;
+use tokio_rustls::TlsConnector;
+
+// Without this a silently dropped SYN holds the lookup for the OS connect timeout
+const TCP_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Binding a port inside a configured range is retried this often on collisions
+const SOURCE_PORT_ATTEMPTS: usize = 16;
+
+// A UDP query is sent this often before the server is given up on, waiting
+// twice as long before each resend as before the previous one
+const UDP_ATTEMPTS: u32 = 3;
+const UDP_RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Copy, Clone)]
+pub enum ReqProtocol {
+    UDP,
+    TCP,
+    // DNS over TLS (RFC 7858), only ever used towards upstreams
+    DoT,
+}
+
+// How queries to upstream servers are sent
+#[derive(Debug, Clone)]
+pub struct QueryOptions {
+    // Inclusive (min, max) range outgoing UDP queries pick their source port
+    // from, for firewalls that only pass DNS replies to known ports
+    pub source_ports: Option<(u16, u16)>,
+    // UDP payload size advertised through EDNS, and so the size of the receive
+    // buffer. None sends plain DNS queries limited to 512 byte responses.
+    pub edns_payload_size: Option<u16>,
+    // How long to wait for a UDP response before resending the query. Also
+    // bounds how long stray datagrams can keep a lookup waiting.
+    pub udp_timeout: Duration,
+    // Upstreams queried over TLS, with the name their certificate is checked
+    // against, e.g. 1.1.1.1:853 => "cloudflare-dns.com"
+    pub tls_server_names: HashMap<SocketAddr, String>,
+}
+
+impl Default for QueryOptions {
+    fn default() -> Self {
+        QueryOptions {
+            source_ports: None,
+            // Avoids IP fragmentation on practically every path (DNS flag day 2020)
+            edns_payload_size: Some(1232),
+            udp_timeout: Duration::from_secs(2),
+            tls_server_names: HashMap::new(),
+        }
+    }
+}
+
+// Per-resolution metadata, for tracking down expensive lookups
+#[derive(Debug, Clone, Default)]
+pub struct ResolveStats {
+    pub nameservers: Vec<SocketAddr>, // In the order they were contacted
+    pub queries: u32,
+    pub max_depth: u32, // Nesting of lookups made to resolve nameserver names
+    pub elapsed: Duration,
+}
+
+impl ResolveStats {
+    pub fn record_query(&mut self, server: SocketAddr, depth: u32) {
+        self.queries += 1;
+        self.max_depth = self.max_depth.max(depth);
+        if !self.nameservers.contains(&server) {
+            self.nameservers.push(server);
+        }
+    }
+}
+
+fn build_query(qname: &str, q_type: QueryType, options: &QueryOptions) -> DNSPacket {
+    let mut dns_packet = DNSPacket::new().with_question(DNSQuestion::new(qname.to_owned(), q_type));
+    // Random so off-path attackers have to guess it to spoof a response
+    dns_packet.header.id = rand::random();
+    dns_packet.header.recur_desired = true;
+    if let Some(size) = options.edns_payload_size {
+        dns_packet.set_udp_payload_size(size);
+    }
+    dns_packet
+}
+
+// Parses the response and checks it answers `request`: same ID and, if the
+// server echoed it, the same question
+fn parse_response<T: PacketBufferTrait>(
+    res_buf: &mut T,
+    request: &DNSPacket,
+    server: SocketAddr,
+) -> Result<DNSPacket> {
+    let response = DNSPacket::from_buffer(res_buf).map_err(|err| {
+        println!("Discarding unparseable response from {}: {}", server, err);
+        err
+    })?;
+    if response.header.id != request.header.id {
+        return Err(eyre!(
+            "Response from {} has ID {}, expected {}",
+            server,
+            response.header.id,
+            request.header.id
+        ));
+    }
+    if let (Some(asked), Some(echoed)) = (request.questions.first(), response.questions.first()) {
+        if normalize_name(&asked.name) != normalize_name(&echoed.name)
+            || asked.q_type != echoed.q_type
+        {
+            return Err(eyre!(
+                "Response from {} is for {:?} {}, expected {:?} {}",
+                server,
+                echoed.q_type,
+                echoed.name,
+                asked.q_type,
+                asked.name
+            ));
+        }
+    }
+    Ok(response)
+}
+
+// Datagrams from anyone but the queried server, or answering another query ID,
+// are stray or spoofed, so they're dropped and the wait for the real reply goes on
+async fn recv_from_server(
+    socket: &mut UdpSocket,
+    capacity: usize,
+    server: SocketAddr,
+    id: u16,
+) -> Result<(VecBuffer, usize)> {
+    loop {
+        let (res_buf, len, src) = VecBuffer::recv_from(socket, capacity).await?;
+        if src != server {
+            println!("Ignoring datagram from {} while waiting on {}", src, server);
+            continue;
+        }
+        if len < 2 || u16::from_be_bytes([res_buf.buf[0], res_buf.buf[1]]) != id {
+            println!("Ignoring response from {} for another query ID", server);
+            continue;
+        }
+        return Ok((res_buf, len));
+    }
+}
+
+// Inclusive range, computed in u32 so a range ending at 65535 doesn't overflow
+fn random_source_port(min: u16, max: u16) -> u16 {
+    rand::thread_rng().gen_range(min as u32, max as u32 + 1) as u16
+}
+
+// Bound to the wildcard address of the server's family
+async fn bind_query_socket(options: &QueryOptions, server: SocketAddr) -> Result<UdpSocket> {
+    let host: IpAddr = if server.is_ipv4() {
+        Ipv4Addr::UNSPECIFIED.into()
+    } else {
+        Ipv6Addr::UNSPECIFIED.into()
+    };
+    let (min, max) = match options.source_ports {
+        Some(range) => range,
+        // An OS-assigned ephemeral port per query, so concurrent lookups don't
+        // collide and responses are harder to spoof
+        None => return Ok(UdpSocket::bind((host, 0)).await?),
+    };
+    if min == 0 || min > max {
+        return Err(eyre!("Invalid source port range {}-{}", min, max));
+    }
+    for _ in 0..SOURCE_PORT_ATTEMPTS {
+        let port = random_source_port(min, max);
+        match UdpSocket::bind((host, port)).await {
+            Ok(socket) => return Ok(socket),
+            Err(err) if err.kind() == io::ErrorKind::AddrInUse => continue,
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Err(eyre!("No free source port found in {}-{}", min, max))
+}
+
+async fn udp_exchange(
+    request: &mut DNSPacket,
+    server: SocketAddr,
+    options: &QueryOptions,
+) -> Result<DNSPacket> {
+    let mut socket = bind_query_socket(options, server).await?;
+    let mut req_buf = ArrayBuffer::new();
+
+    request.write(&mut req_buf)?;
+
+    // Without EDNS the server must fit its response into 512 bytes
+    let capacity = request
+        .udp_payload_size()
+        .unwrap_or(MIN_UDP_PAYLOAD)
+        .max(MIN_UDP_PAYLOAD) as usize;
+    let mut attempt = 0;
+    // Resent from the same socket, so a late reply to an earlier attempt still counts
+    let (mut res_buf, len) = loop {
+        socket
+            .send_to(&req_buf.buf[0..req_buf.pos()], server)
+            .await?;
+        match timeout(
+            options.udp_timeout,
+            recv_from_server(&mut socket, capacity, server, request.header.id),
+        )
+        .await
+        {
+            Ok(received) => break received?,
+            Err(_) if attempt + 1 < UDP_ATTEMPTS => {
+                let backoff = UDP_RETRY_BACKOFF * 2u32.pow(attempt);
+                println!("No response from {}, retrying in {:?}", server, backoff);
+                delay_for(backoff).await;
+                attempt += 1;
+            }
+            Err(_) => return Err(io::Error::from(io::ErrorKind::TimedOut).into()),
+        }
+    };
+    if len == capacity {
+        // A datagram that fills the whole buffer may have been cut off mid-record,
+        // so only the header is trusted and the response is treated as truncated
+        let mut res_packet = DNSPacket::new();
+        res_packet.header.read(&mut res_buf)?;
+        res_packet.header.truncated_msg = true;
+        return Ok(res_packet);
+    }
+    parse_response(&mut res_buf, request, server)
+}
+
+async fn tcp_exchange(request: &mut DNSPacket, server: SocketAddr) -> Result<DNSPacket> {
+    let mut socket = match timeout(TCP_CONNECT_TIMEOUT, TcpStream::connect(server)).await {
+        Ok(socket) => socket?,
+        Err(_) => return Err(io::Error::from(io::ErrorKind::TimedOut).into()),
+    };
+    let mut req_buf = VecBuffer::new();
+
+    request.write(&mut req_buf)?;
+    req_buf.to_socket(&mut socket).await?;
+
+    let mut res_buf = VecBuffer::from_socket(&mut socket).await?;
+    parse_response(&mut res_buf, request, server)
+}
+
+// Same framing as TCP, inside a TLS session whose certificate must be valid for
+// `server_name` under the bundled web PKI roots
+async fn dot_exchange(
+    request: &mut DNSPacket,
+    server: SocketAddr,
+    server_name: &str,
+) -> Result<DNSPacket> {
+    let socket = match timeout(TCP_CONNECT_TIMEOUT, TcpStream::connect(server)).await {
+        Ok(socket) => socket?,
+        Err(_) => return Err(io::Error::from(io::ErrorKind::TimedOut).into()),
+    };
+    let mut tls_config = ClientConfig::new();
+    tls_config
+        .root_store
+        .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    let dns_name = DNSNameRef::try_from_ascii_str(server_name)
+        .map_err(|_| eyre!("Invalid TLS server name {:?}", server_name))?;
+    let mut socket = TlsConnector::from(Arc::new(tls_config))
+        .connect(dns_name, socket)
+        .await?;
+    let mut req_buf = VecBuffer::new();
+
+    request.write(&mut req_buf)?;
+    req_buf.to_socket(&mut socket).await?;
+
+    let mut res_buf = VecBuffer::from_socket(&mut socket).await?;
+    parse_response(&mut res_buf, request, server)
+}
+
+async fn udp_lookup(
+    qname: &str,
+    q_type: QueryType,
+    server: SocketAddr,
+    options: &QueryOptions,
+) -> Result<DNSPacket> {
+    udp_exchange(&mut build_query(qname, q_type, options), server, options).await
+}
+
+async fn tcp_lookup(
+    qname: &str,
+    q_type: QueryType,
+    server: SocketAddr,
+    options: &QueryOptions,
+) -> Result<DNSPacket> {
+    tcp_exchange(&mut build_query(qname, q_type, options), server).await
+}
+
+pub async fn dot_lookup(
+    qname: &str,
+    q_type: QueryType,
+    server: SocketAddr,
+    options: &QueryOptions,
+) -> Result<DNSPacket> {
+    let server_name = options
+        .tls_server_names
+        .get(&server)
+        .ok_or_else(|| eyre!("No TLS server name configured for {}", server))?;
+    dot_exchange(
+        &mut build_query(qname, q_type, options),
+        server,
+        server_name,
+    )
+    .await
+}
+
+// Asks the primary for the zone's SOA and reports whether its serial is newer
+// than `local_serial`, i.e. whether the zone has to be transferred again. Used
+// after a NOTIFY or when the SOA refresh timer fires.
+pub async fn zone_needs_transfer(
+    zone: &str,
+    local_serial: u32,
+    server: SocketAddr,
+    protocol: ReqProtocol,
+    options: &QueryOptions,
+) -> Result<bool> {
+    let response = query_server(zone, QueryType::SOA, server, protocol, options).await?;
+    let serial = response
+        .answers
+        .iter()
+        .find_map(|record| match record {
+            DNSRecord::SOA { serial, .. } => Some(*serial),
+            _ => None,
+        })
+        .ok_or_else(|| eyre!("{} returned no SOA for {}", server, zone))?;
+    Ok(serial_newer(serial, local_serial))
+}
+
+// True when the server couldn't be reached over TCP at all, as opposed to
+// answering badly, so the same server is still worth a try over UDP
+pub fn is_tcp_unreachable(err: &eyre::Report) -> bool {
+    match err.downcast_ref::<io::Error>() {
+        Some(err) => matches!(
+            err.kind(),
+            io::ErrorKind::ConnectionRefused | io::ErrorKind::TimedOut
+        ),
+        None => false,
+    }
+}
+
+// Sends a single query to `server` and returns its response as-is, without
+// following referrals (like `dig @server`). A truncated UDP response is asked
+// again over TCP, which is how recursive lookups get the full answer.
+pub async fn query_server(
+    qname: &str,
+    q_type: QueryType,
+    server: SocketAddr,
+    protocol: ReqProtocol,
+    options: &QueryOptions,
+) -> Result<DNSPacket> {
+    match protocol {
+        ReqProtocol::UDP => {
+            let response = udp_lookup(qname, q_type, server, options).await?;
+            if response.header.truncated_msg {
+                println!(
+                    "UDP response from {} was truncated, retrying over TCP",
+                    server
+                );
+                return tcp_lookup(qname, q_type, server, options).await;
+            }
+            Ok(response)
+        }
+        ReqProtocol::TCP => tcp_lookup(qname, q_type, server, options).await,
+        ReqProtocol::DoT => dot_lookup(qname, q_type, server, options).await,
+    }
+}
+
+// Tells a secondary that `zone` changed (RFC 1996), so it refreshes without
+// waiting for the SOA refresh timer. Returns the secondary's acknowledgement.
+pub async fn send_notify(
+    zone: &str,
+    q_type: QueryType,
+    server: SocketAddr,
+    protocol: ReqProtocol,
+    options: &QueryOptions,
+) -> Result<DNSPacket> {
+    let mut notify = DNSPacket::new().with_question(DNSQuestion::new(zone.to_owned(), q_type));
+    notify.header.id = rand::random();
+    notify.header.opcode = OpCode::NOTIFY;
+    notify.header.auth_answer = true;
+    let response = match protocol {
+        ReqProtocol::UDP => udp_exchange(&mut notify, server, options).await?,
+        ReqProtocol::TCP => tcp_exchange(&mut notify, server).await?,
+        ReqProtocol::DoT => {
+            let server_name = options
+                .tls_server_names
+                .get(&server)
+                .ok_or_else(|| eyre!("No TLS server name configured for {}", server))?;
+            dot_exchange(&mut notify, server, server_name).await?
+        }
+    };
+    if response.header.opcode != OpCode::NOTIFY {
+        return Err(eyre!(
+            "Expected a NOTIFY response from {}, got opcode {:?}",
+            server,
+            response.header.opcode
+        ));
+    }
+    Ok(response)
+}
+
+pub fn recursive_lookup<'a>(
+    qname: &'a str,
+    q_type: QueryType,
+    protocol: ReqProtocol,
+    cache: &'a mut DNSCache,
+    stats: &'a mut ResolveStats,
+    config: &'a ServerConfig,
+    depth: u32,
+) -> BoxFuture<'a, Result<DNSPacket>> {
+    Box::pin(async move {
+        if depth > config.max_recursion_depth {
+            return Err(eyre!(
+                "Maximum recursion depth {} exceeded while resolving {}",
+                config.max_recursion_depth,
+                qname
+            ));
+        }
+        let mut ns = IpAddr::V4(config.root_server);
+        // Untried servers of the current delegation, to fail over to if `ns` is lame
+        let mut candidates = vec![ns];
+        // Servers already queried for the current zone. A referral offering only
+        // these again makes no progress, e.g. a zone delegated to itself.
+        let mut tried = HashSet::new();
+        // The zone the current nameserver was delegated, starting from the root
+        let mut zone = String::new();
+        if let Some(result) = cache.get_records(qname, q_type) {
+            println!("Found result in cache");
+            return Ok(result);
+        }
+        // } else {
+        //     if let Some(a_record) = cache.get_nearest_a_record(qname, q_type) {
+        //         ns = a_record;
+        //         println!("Chaning NS using cache");
+        //     }
+        // }
+        loop {
+            println!("attempting lookup of {:?} {} with ns {}", q_type, qname, ns);
+
+            let server = SocketAddr::from((ns, config.nameserver_port));
+
+            if stats.queries >= config.query_budget {
+                return Err(eyre!("Query budget exhausted while resolving {}", qname));
+            }
+            stats.record_query(server, depth);
+            tried.insert(ns);
+
+            let query_protocol = config.upstream_protocol(qname, q_type, server, protocol);
+            let sent_at = Instant::now();
+            let result =
+                match query_server(qname, q_type, server, query_protocol, &config.query_options)
+                    .await
+                {
+                    // Some authoritative servers only speak UDP
+                    Err(err)
+                        if matches!(query_protocol, ReqProtocol::TCP)
+                            && is_tcp_unreachable(&err) =>
+                    {
+                        println!("TCP to {} failed ({}), falling back to UDP", server, err);
+                        query_server(
+                            qname,
+                            q_type,
+                            server,
+                            ReqProtocol::UDP,
+                            &config.query_options,
+                        )
+                        .await
+                    }
+                    result => result,
+                };
+            let mut response = match result {
+                Ok(response) => {
+                    cache.upstreams.record(server.ip(), sent_at.elapsed());
+                    response
+                }
+                Err(err) => {
+                    cache.upstreams.record_failure(server.ip());
+                    println!("Query to {} failed: {}", server, err);
+                    candidates.retain(|addr| *addr != ns);
+                    match cache.upstreams.choose(&candidates, config.randomize_ns) {
+                        Some(next_ns) => {
+                            ns = next_ns;
+                            continue;
+                        }
+                        None => return Err(err),
+                    }
+                }
+            };
+
+            if response.is_lame_for(&zone) {
+                println!("Lame delegation: {} does not serve {:?}", server, zone);
+                candidates.retain(|addr| *addr != ns);
+                match cache.upstreams.choose(&candidates, config.randomize_ns) {
+                    Some(next_ns) => {
+                        ns = next_ns;
+                        continue;
+                    }
+                    None => {
+                        return Err(eyre!("Every nameserver for {:?} is lame", zone));
+                    }
+                }
+            }
+
+            response.normalize_rrset_ttls();
+            if response.is_final_answer() {
+                cache.set_records(qname, q_type, &response, &zone);
+            } else {
+                cache.set_rrsets(&response, &zone);
+            }
+
+            if !response.answers.is_empty() && response.header.res_code == RCode::NOERROR {
+                return Ok(response);
+            }
+
+            if response.header.res_code == RCode::NXDOMAIN {
+                return Ok(response);
+            }
+
+            // NODATA, e.g. for an empty non-terminal, is as final as NXDOMAIN but
+            // must keep its NOERROR rcode
+            if response.is_nodata() {
+                return Ok(response);
+            }
+
+            if let Some((delegated_zone, _)) = response.get_ns(qname).next() {
+                if normalize_name(delegated_zone) != normalize_name(&zone) {
+                    tried.clear();
+                }
+                zone = delegated_zone.to_owned();
+            }
+
+            let mut offered = response
+                .get_all_resolved_ns(qname)
+                .map(IpAddr::V4)
+                .collect::<Vec<IpAddr>>();
+            if !config.disable_ipv6 {
+                offered.extend(response.get_all_resolved_ns_v6(qname).map(IpAddr::V6));
+            }
+            let glue = offered
+                .iter()
+                .filter(|addr| !tried.contains(*addr))
+                .copied()
+                .collect::<Vec<IpAddr>>();
+            if glue.is_empty() && !offered.is_empty() {
+                return Err(eyre!(
+                    "Referral loop for {:?} while resolving {}",
+                    zone,
+                    qname
+                ));
+            }
+            if let Some(new_ns) = cache.upstreams.choose(&glue, config.randomize_ns) {
+                ns = new_ns;
+                candidates = glue;
+                continue;
+            }
+
+            let new_ns_name = match response.get_unresolved_ns(qname) {
+                Some(name) => name,
+                None => return Ok(response),
+            };
+
+            let recursive_response = recursive_lookup(
+                new_ns_name,
+                QueryType::A,
+                protocol,
+                cache,
+                stats,
+                config,
+                depth + 1,
+            )
+            .await?;
+            let mut new_ns = recursive_response.get_random_a().map(IpAddr::V4);
+            if new_ns.is_none() && !config.disable_ipv6 {
+                // The nameserver may only be reachable over IPv6
+                let recursive_response = recursive_lookup(
+                    new_ns_name,
+                    QueryType::AAAA,
+                    protocol,
+                    cache,
+                    stats,
+                    config,
+                    depth + 1,
+                )
+                .await?;
+                new_ns = recursive_response.get_random_aaaa().map(IpAddr::V6);
+            }
+
+            if let Some(new_ns) = new_ns {
+                if tried.contains(&new_ns) {
+                    return Err(eyre!(
+                        "Referral loop for {:?} while resolving {}",
+                        zone,
+                        qname
+                    ));
+                }
+                ns = new_ns;
+                candidates = vec![new_ns];
+            } else {
+                return Ok(response);
+            }
+        }
+    })
+}
+
+// Every lookup slot was taken and the answer wasn't cached, so no lookup was
+// attempted. Answered with a bare SERVFAIL rather than blaming the authorities.
+#[derive(Debug)]
+pub struct Overloaded;
+
+impl fmt::Display for Overloaded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Overloaded and the answer is not cached")
+    }
+}
+
+impl std::error::Error for Overloaded {}
+
+// Picks the resolution path for a question: forwarded to a configured upstream
+// for conditionally forwarded zones, full recursion otherwise.
+pub async fn resolve_with_stats(
+    question: &DNSQuestion,
+    protocol: ReqProtocol,
+    cache: &mut DNSCache,
+    config: &ServerConfig,
+) -> Result<(DNSPacket, ResolveStats)> {
+    let start = Instant::now();
+    let mut stats = ResolveStats::default();
+    // Whatever RRsets are cached for the name make up the answer, like most
+    // resolvers do instead of fetching every type (RFC 8482 section 4.3)
+    if question.q_type == QueryType::ANY {
+        if let Some(result) = cache.get_any(&question.name) {
+            println!("Answering ANY for {} from cache", question.name);
+            return Ok((result, stats));
+        }
+    }
+    let _permit = match &config.lookup_slots {
+        Some(slots) => match slots.try_acquire() {
+            Ok(permit) => Some(permit),
+            Err(_) => {
+                println!("Overloaded, answering {} from cache only", question.name);
+                let result = cache
+                    .get_records(&question.name, question.q_type)
+                    .ok_or(Overloaded)?;
+                return Ok((result, stats));
+            }
+        },
+        None => None,
+    };
+    let result = if let Some((zone, server)) = config.forwarder_for(&question.name) {
+        match cache.get_records(&question.name, question.q_type) {
+            Some(result) => result,
+            None => {
+                println!("Forwarding {} to {}", question.name, server);
+                stats.record_query(server, 0);
+                let result = query_server(
+                    &question.name,
+                    question.q_type,
+                    server,
+                    config.upstream_protocol(&question.name, question.q_type, server, protocol),
+                    &config.query_options,
+                )
+                .await?;
+                // The forwarder is only trusted for data in the zone it was configured for
+                cache.set_records(&question.name, question.q_type, &result, zone);
+                result
+            }
+        }
+    } else {
+        let mut result = recursive_lookup(
+            &question.name,
+            question.q_type,
+            protocol,
+            cache,
+            &mut stats,
+            config,
+            0,
+        )
+        .await?;
+        if config.filter_bogons {
+            let dropped = result.drop_bogon_answers();
+            if dropped > 0 {
+                println!("Dropped {} bogon answers for {}", dropped, question.name);
+            }
+        }
+        result
+    };
+    stats.elapsed = start.elapsed();
+    Ok((result, stats))
+}
+
+// Resolver for library users that don't run the server, e.g. CLI tools:
+//     let response = Resolver::new("1.1.1.1:53".parse()?).resolve_blocking("example.com", QueryType::A)?;
+#[derive(Debug, Clone)]
+pub struct Resolver {
+    cache: DNSCache,
+    config: ServerConfig,
+}
+
+impl Default for Resolver {
+    fn default() -> Self {
+        Resolver::recursive()
+    }
+}
+
+impl Resolver {
+    // Forwards every question to `upstream`
+    pub fn new(upstream: SocketAddr) -> Resolver {
+        Resolver::with_config(ServerConfig {
+            forward_zones: vec![(String::new(), upstream)],
+            ..ServerConfig::default()
+        })
+    }
+
+    // Resolves iteratively starting at the root servers
+    pub fn recursive() -> Resolver {
+        Resolver::with_config(ServerConfig::default())
+    }
+
+    pub fn with_config(config: ServerConfig) -> Resolver {
+        Resolver {
+            cache: DNSCache::new(),
+            config,
+        }
+    }
+
+    pub async fn resolve(&self, qname: &str, q_type: QueryType) -> Result<DNSPacket> {
+        let question = DNSQuestion::new(qname.to_owned(), q_type);
+        let mut cache = self.cache.clone();
+        resolve_with_stats(&question, ReqProtocol::UDP, &mut cache, &self.config)
+            .await
+            .map(|(packet, _)| packet)
+    }
+
+    // Runs the lookup on a private runtime. Must not be called from within one.
+    pub fn resolve_blocking(&self, qname: &str, q_type: QueryType) -> Result<DNSPacket> {
+        let mut runtime = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()?;
+        runtime.block_on(self.resolve(qname, q_type))
+    }
+}
+
+// Resolves every (name, type) pair with at most `config.batch_concurrency`
+// lookups in flight. Results come back in the same order as `names`.
+pub async fn resolve_batch(
+    names: &[(String, QueryType)],
+    protocol: ReqProtocol,
+    cache: &DNSCache,
+    config: &ServerConfig,
+) -> Vec<Result<DNSPacket>> {
+    let semaphore = Semaphore::new(config.batch_concurrency.max(1));
+    join_all(names.iter().map(|(qname, q_type)| {
+        let semaphore = &semaphore;
+        let mut cache = cache.clone();
+        async move {
+            let _permit = semaphore.acquire().await;
+            let question = DNSQuestion::new(qname.clone(), *q_type);
+            resolve_with_stats(&question, protocol, &mut cache, config)
+                .await
+                .map(|(packet, _)| packet)
+        }
+    }))
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "testing")]
+    use crate::testing::MockNameServer;
+
+    #[tokio::test]
+    async fn overloaded_lookups_are_answered_from_cache_or_fail_as_overloaded() {
+        let config = ServerConfig {
+            lookup_slots: Some(Arc::new(Semaphore::new(0))),
+            ..ServerConfig::default()
+        };
+        let mut cache = DNSCache::new();
+        let question = DNSQuestion::new("www.example".to_owned(), QueryType::A);
+        let err = resolve_with_stats(&question, ReqProtocol::UDP, &mut cache, &config)
+            .await
+            .err()
+            .unwrap();
+        assert!(err.downcast_ref::<Overloaded>().is_some());
+
+        let mut answer = DNSPacket::new();
+        answer.answers.push(DNSRecord::new_a(
+            "www.example",
+            Ipv4Addr::new(192, 0, 2, 1),
+            300,
+        ));
+        cache.set_records("www.example", QueryType::A, &answer, "example");
+        let (result, _) = resolve_with_stats(&question, ReqProtocol::UDP, &mut cache, &config)
+            .await
+            .unwrap();
+        assert_eq!(result.answers.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn any_is_answered_with_every_cached_rrset() {
+        let mut cache = DNSCache::new();
+        let a = DNSRecord::new_a("example.com", Ipv4Addr::new(192, 0, 2, 1), 300);
+        let mx = DNSRecord::MX {
+            name: "example.com".to_owned(),
+            q_type: QueryType::MX,
+            class: 1,
+            ttl: 300,
+            len: 0,
+            priority: 10,
+            host: "mail.example.com".to_owned(),
+        };
+        for record in [a.clone(), mx.clone()] {
+            let mut answer = DNSPacket::new();
+            let q_type = record.get_type();
+            answer.answers.push(record);
+            cache.set_records("example.com", q_type, &answer, "example.com");
+        }
+        let config = ServerConfig {
+            query_budget: 0,
+            ..ServerConfig::default()
+        };
+        let question = DNSQuestion::new("example.com".to_owned(), QueryType::ANY);
+        let (result, stats) = resolve_with_stats(&question, ReqProtocol::UDP, &mut cache, &config)
+            .await
+            .unwrap();
+        assert_eq!(stats.queries, 0);
+        assert_eq!(result.answers.len(), 2);
+        assert!(result.answers.contains(&a));
+        assert!(result.answers.contains(&mx));
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    async fn truncated_udp_response_is_retried_over_tcp() {
+        let server = MockNameServer::bind("[::1]:0".parse().unwrap())
+            .await
+            .unwrap();
+        let mut answer = DNSPacket::new();
+        for i in 0..40 {
+            answer.answers.push(DNSRecord::new_a(
+                "big.example",
+                Ipv4Addr::new(192, 0, 2, i),
+                300,
+            ));
+        }
+        server.answer("big.example", QueryType::A, answer);
+        // Without EDNS the answer can't fit the 512 bytes allowed over UDP
+        let options = QueryOptions {
+            edns_payload_size: None,
+            ..QueryOptions::default()
+        };
+        let response = query_server(
+            "big.example",
+            QueryType::A,
+            server.addr(),
+            ReqProtocol::UDP,
+            &options,
+        )
+        .await
+        .unwrap();
+        assert!(!response.header.truncated_msg);
+        assert_eq!(response.answers.len(), 40);
+        assert_eq!(server.tcp_queries(), 1);
+    }
+
+    // Delegation of `zone` to `ns_name`, with glue for it
+    #[cfg(feature = "testing")]
+    fn referral(zone: &str, ns_name: &str, glue: Ipv4Addr) -> DNSPacket {
+        let mut packet = DNSPacket::new();
+        packet.authority.push(DNSRecord::NS {
+            name: zone.to_owned(),
+            q_type: QueryType::NS,
+            class: 1,
+            ttl: 3600,
+            len: 0,
+            host: ns_name.to_owned(),
+        });
+        packet.addtional.push(DNSRecord::new_a(ns_name, glue, 3600));
+        packet
+    }
+
+    // Mock nameservers sharing one port on 127.0.0.2, 127.0.0.3, ...
+    #[cfg(feature = "testing")]
+    async fn mock_servers(count: u8) -> Vec<MockNameServer> {
+        let first = MockNameServer::bind("127.0.0.2:0".parse().unwrap())
+            .await
+            .unwrap();
+        let port = first.addr().port();
+        let mut servers = vec![first];
+        for i in 1..count {
+            let addr = SocketAddr::from((Ipv4Addr::new(127, 0, 0, 2 + i), port));
+            servers.push(MockNameServer::bind(addr).await.unwrap());
+        }
+        servers
+    }
+
+    #[cfg(feature = "testing")]
+    fn hierarchy_config(root: &MockNameServer) -> ServerConfig {
+        let root_server = match root.addr().ip() {
+            IpAddr::V4(addr) => addr,
+            IpAddr::V6(_) => unreachable!(),
+        };
+        ServerConfig {
+            root_server,
+            nameserver_port: root.addr().port(),
+            ..ServerConfig::default()
+        }
+    }
+
+    // Root, "com" and "example.com" servers, the last one answering www.example.com
+    #[cfg(feature = "testing")]
+    async fn three_level_hierarchy() -> Vec<MockNameServer> {
+        let servers = mock_servers(3).await;
+        let (root, tld, auth) = (&servers[0], &servers[1], &servers[2]);
+        root.answer_zone(
+            "com",
+            referral("com", "a.gtld.test", Ipv4Addr::new(127, 0, 0, 3)),
+        );
+        tld.answer_zone(
+            "example.com",
+            referral(
+                "example.com",
+                "ns1.example.com",
+                Ipv4Addr::new(127, 0, 0, 4),
+            ),
+        );
+        let mut answer = DNSPacket::new();
+        answer.header.auth_answer = true;
+        answer.answers.push(DNSRecord::new_a(
+            "www.example.com",
+            Ipv4Addr::new(192, 0, 2, 80),
+            300,
+        ));
+        auth.answer("www.example.com", QueryType::A, answer);
+        servers
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    async fn follows_referrals_from_the_root_down() {
+        let servers = three_level_hierarchy().await;
+        let (root, tld, auth) = (&servers[0], &servers[1], &servers[2]);
+        let config = hierarchy_config(root);
+        let mut cache = DNSCache::new();
+        let mut stats = ResolveStats::default();
+        let response = recursive_lookup(
+            "www.example.com",
+            QueryType::A,
+            ReqProtocol::UDP,
+            &mut cache,
+            &mut stats,
+            &config,
+            0,
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.get_random_a(), Some(Ipv4Addr::new(192, 0, 2, 80)));
+        assert_eq!(
+            stats.nameservers,
+            vec![root.addr(), tld.addr(), auth.addr()]
+        );
+        // The delegations are cached on the way down
+        assert!(cache.get_records("ns1.example.com", QueryType::A).is_some());
+        assert!(cache.get_records("www.example.com", QueryType::A).is_some());
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    async fn resolves_a_glue_when_only_ipv6_glue_is_offered_and_ipv6_is_disabled() {
+        let servers = mock_servers(2).await;
+        let (root, tld) = (&servers[0], &servers[1]);
+        let mut v6_referral = referral("com", "a.gtld.test", Ipv4Addr::new(127, 0, 0, 3));
+        v6_referral.addtional = vec![DNSRecord::new_aaaa(
+            "a.gtld.test",
+            "2001:db8::53".parse().unwrap(),
+            3600,
+        )];
+        root.answer_zone("com", v6_referral);
+        let mut glue = DNSPacket::new();
+        glue.header.auth_answer = true;
+        glue.answers.push(DNSRecord::new_a(
+            "a.gtld.test",
+            Ipv4Addr::new(127, 0, 0, 3),
+            3600,
+        ));
+        root.answer("a.gtld.test", QueryType::A, glue);
+        let mut answer = DNSPacket::new();
+        answer.header.auth_answer = true;
+        answer.answers.push(DNSRecord::new_a(
+            "www.example.com",
+            Ipv4Addr::new(192, 0, 2, 80),
+            300,
+        ));
+        tld.answer("www.example.com", QueryType::A, answer);
+
+        let config = ServerConfig {
+            disable_ipv6: true,
+            ..hierarchy_config(root)
+        };
+        let mut cache = DNSCache::new();
+        let mut stats = ResolveStats::default();
+        let response = recursive_lookup(
+            "www.example.com",
+            QueryType::A,
+            ReqProtocol::UDP,
+            &mut cache,
+            &mut stats,
+            &config,
+            0,
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.get_random_a(), Some(Ipv4Addr::new(192, 0, 2, 80)));
+        // The AAAA glue was never tried, the nameserver's A record was looked up instead
+        assert_eq!(stats.nameservers, vec![root.addr(), tld.addr()]);
+        assert_eq!(stats.queries, 3);
+        assert_eq!(stats.max_depth, 1);
+    }
+
+    #[cfg(feature = "testing")]
+    async fn lookup(config: &ServerConfig, qname: &str) -> (Result<DNSPacket>, ResolveStats) {
+        let mut cache = DNSCache::new();
+        let mut stats = ResolveStats::default();
+        let result = recursive_lookup(
+            qname,
+            QueryType::A,
+            ReqProtocol::UDP,
+            &mut cache,
+            &mut stats,
+            config,
+            0,
+        )
+        .await;
+        (result, stats)
+    }
+
+    // The root delegates "test" to a nameserver inside it without glue, so
+    // finding the nameserver's address needs the same delegation again
+    #[cfg(feature = "testing")]
+    async fn glueless_loop() -> MockNameServer {
+        let root = mock_servers(1).await.remove(0);
+        let mut delegation = referral("test", "ns.sub.test", Ipv4Addr::LOCALHOST);
+        delegation.addtional.clear();
+        root.answer_zone("test", delegation);
+        root
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    async fn pathological_delegation_runs_out_of_query_budget() {
+        let root = glueless_loop().await;
+        let config = ServerConfig {
+            query_budget: 5,
+            max_recursion_depth: 100,
+            ..hierarchy_config(&root)
+        };
+        let (result, stats) = lookup(&config, "www.test").await;
+        let err = result.err().unwrap();
+        assert!(err.to_string().contains("budget"), "{}", err);
+        assert_eq!(stats.queries, 5);
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    async fn query_server_asks_a_single_server_directly() {
+        let server = mock_servers(1).await.remove(0);
+        let mut answer = DNSPacket::new();
+        answer.answers.push(DNSRecord::new_a(
+            "www.example",
+            Ipv4Addr::new(192, 0, 2, 1),
+            300,
+        ));
+        server.answer("www.example", QueryType::A, answer);
+        let response = query_server(
+            "www.example",
+            QueryType::A,
+            server.addr(),
+            ReqProtocol::UDP,
+            &QueryOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.get_random_a(), Some(Ipv4Addr::new(192, 0, 2, 1)));
+        assert_eq!(response.questions[0].name, "www.example");
+    }
+
+    // Answers one UDP query with whatever `reply` makes of the request bytes
+    async fn raw_server(reply: impl FnOnce(&[u8]) -> Vec<u8> + Send + 'static) -> SocketAddr {
+        let mut socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            let (len, src) = socket.recv_from(&mut buf).await.unwrap();
+            let response = reply(&buf[..len]);
+            socket.send_to(&response, src).await.unwrap();
+        });
+        addr
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    async fn server_refusing_connections_over_tcp_is_asked_over_udp() {
+        // Nothing listens for TCP on the UDP responder's port
+        let server = raw_server(|request| {
+            let mut response = DNSPacket::from_bytes(request).unwrap();
+            response.header.query_response = true;
+            response.header.auth_answer = true;
+            response.answers.push(DNSRecord::new_a(
+                "www.example",
+                Ipv4Addr::new(192, 0, 2, 1),
+                300,
+            ));
+            response.to_bytes().unwrap()
+        })
+        .await;
+        let config = ServerConfig {
+            root_server: Ipv4Addr::LOCALHOST,
+            nameserver_port: server.port(),
+            ..ServerConfig::default()
+        };
+        let mut cache = DNSCache::new();
+        let mut stats = ResolveStats::default();
+        let response = recursive_lookup(
+            "www.example",
+            QueryType::A,
+            ReqProtocol::TCP,
+            &mut cache,
+            &mut stats,
+            &config,
+            0,
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.answers.len(), 1);
+    }
+
+    #[test]
+    fn only_unreachable_tcp_servers_are_retried_over_udp() {
+        let refused = eyre::Report::from(io::Error::from(io::ErrorKind::ConnectionRefused));
+        assert!(is_tcp_unreachable(&refused));
+        let timed_out = eyre::Report::from(io::Error::from(io::ErrorKind::TimedOut));
+        assert!(is_tcp_unreachable(&timed_out));
+        assert!(!is_tcp_unreachable(&eyre!("Malformed response")));
+    }
+
+    // A reply for `request` answering www.example with `addr`
+    fn reply_with(request: &[u8], addr: Ipv4Addr) -> Vec<u8> {
+        let mut response = DNSPacket::from_bytes(request).unwrap();
+        response.header.query_response = true;
+        response
+            .answers
+            .push(DNSRecord::new_a("www.example", addr, 300));
+        response.to_bytes().unwrap()
+    }
+
+    #[tokio::test]
+    async fn silent_server_times_out() {
+        // Bound, so queries aren't refused, but never read
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let options = QueryOptions {
+            udp_timeout: Duration::from_millis(20),
+            ..QueryOptions::default()
+        };
+        let started = Instant::now();
+        let err = udp_lookup(
+            "www.example",
+            QueryType::A,
+            silent.local_addr().unwrap(),
+            &options,
+        )
+        .await
+        .unwrap_err();
+        let err = err.downcast_ref::<io::Error>().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn datagrams_from_other_sources_are_ignored() {
+        let mut server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut spoofer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            let (len, src) = server.recv_from(&mut buf).await.unwrap();
+            // Right ID, wrong sender, and it gets there first
+            let spoofed = reply_with(&buf[..len], Ipv4Addr::new(203, 0, 113, 66));
+            spoofer.send_to(&spoofed, src).await.unwrap();
+            let legit = reply_with(&buf[..len], Ipv4Addr::new(192, 0, 2, 1));
+            server.send_to(&legit, src).await.unwrap();
+        });
+        let response = udp_lookup("www.example", QueryType::A, addr, &QueryOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            response.answers,
+            vec![DNSRecord::new_a(
+                "www.example",
+                Ipv4Addr::new(192, 0, 2, 1),
+                300
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn responses_for_other_ids_are_ignored() {
+        let mut server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            let (len, src) = server.recv_from(&mut buf).await.unwrap();
+            let mut stray = reply_with(&buf[..len], Ipv4Addr::new(203, 0, 113, 66));
+            stray[1] = stray[1].wrapping_add(1);
+            server.send_to(&stray, src).await.unwrap();
+            let legit = reply_with(&buf[..len], Ipv4Addr::new(192, 0, 2, 1));
+            server.send_to(&legit, src).await.unwrap();
+        });
+        let response = udp_lookup("www.example", QueryType::A, addr, &QueryOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(response.get_random_a(), Some(Ipv4Addr::new(192, 0, 2, 1)));
+    }
+
+    #[tokio::test]
+    async fn response_must_echo_the_question() {
+        let server = raw_server(|request| {
+            let mut response = DNSPacket::from_bytes(request).unwrap();
+            response.header.query_response = true;
+            response.questions[0].name = "other.example".to_owned();
+            response.to_bytes().unwrap()
+        })
+        .await;
+        let result = udp_lookup(
+            "www.example",
+            QueryType::A,
+            server,
+            &QueryOptions::default(),
+        )
+        .await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn notify_carries_the_zone_soa_question() {
+        let (sent, received) = std::sync::mpsc::channel();
+        let server = raw_server(move |request| {
+            let mut packet = DNSPacket::from_bytes(request).unwrap();
+            sent.send(packet.clone()).unwrap();
+            packet.header.query_response = true;
+            packet.to_bytes().unwrap()
+        })
+        .await;
+        let response = send_notify(
+            "example.com",
+            QueryType::SOA,
+            server,
+            ReqProtocol::UDP,
+            &QueryOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.header.opcode, OpCode::NOTIFY);
+
+        let notify = received.recv().unwrap();
+        assert_eq!(notify.header.opcode, OpCode::NOTIFY);
+        assert!(notify.header.auth_answer);
+        assert_eq!(notify.questions.len(), 1);
+        assert_eq!(notify.questions[0].name, "example.com");
+        assert_eq!(notify.questions[0].q_type, QueryType::SOA);
+    }
+
+    #[tokio::test]
+    async fn datagram_filling_the_buffer_is_treated_as_truncated() {
+        // A plain 512 byte reply without TC, it could have been cut off anywhere
+        let server = raw_server(|request| {
+            let mut response = vec![request[0], request[1], 0x81, 0x80];
+            response.resize(MIN_UDP_PAYLOAD as usize, 0);
+            response
+        })
+        .await;
+        let options = QueryOptions {
+            edns_payload_size: None,
+            ..QueryOptions::default()
+        };
+        let response = udp_lookup("www.example", QueryType::A, server, &options)
+            .await
+            .unwrap();
+        // Which makes query_server retry over TCP
+        assert!(response.header.truncated_msg);
+        assert!(response.answers.is_empty());
+    }
+
+    #[cfg(feature = "testing")]
+    fn a_answer(qname: &str, addr: Ipv4Addr) -> DNSPacket {
+        let mut answer = DNSPacket::new();
+        answer.header.auth_answer = true;
+        answer.answers.push(DNSRecord::new_a(qname, addr, 300));
+        answer
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    async fn lame_nameserver_is_skipped_for_the_next_one() {
+        let servers = mock_servers(3).await;
+        let (root, lame, good) = (&servers[0], &servers[1], &servers[2]);
+        let mut delegation = referral("example", "ns1.example", Ipv4Addr::new(127, 0, 0, 3));
+        let second = referral("example", "ns2.example", Ipv4Addr::new(127, 0, 0, 4));
+        delegation.authority.extend(second.authority);
+        delegation.addtional.extend(second.addtional);
+        root.answer_zone("example", delegation);
+        let mut refused = DNSPacket::new();
+        refused.header.res_code = RCode::REFUSED;
+        lame.answer_zone("example", refused);
+        good.answer(
+            "www.example",
+            QueryType::A,
+            a_answer("www.example", Ipv4Addr::new(192, 0, 2, 1)),
+        );
+
+        let config = ServerConfig {
+            randomize_ns: false,
+            ..hierarchy_config(root)
+        };
+        let (result, stats) = lookup(&config, "www.example").await;
+        assert_eq!(result.unwrap().answers.len(), 1);
+        // ns1 comes first in the referral and gets asked before ns2
+        assert_eq!(stats.queries, 3);
+        assert!(stats.nameservers.contains(&lame.addr()));
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    async fn bogon_answers_are_filtered_when_asked_to() {
+        let root = mock_servers(1).await.remove(0);
+        let mut poisoned = a_answer("www.example.com", Ipv4Addr::new(10, 0, 0, 1));
+        poisoned.answers.push(DNSRecord::new_a(
+            "www.example.com",
+            Ipv4Addr::new(93, 184, 216, 34),
+            300,
+        ));
+        root.answer("www.example.com", QueryType::A, poisoned);
+        let question = DNSQuestion::new("www.example.com".to_owned(), QueryType::A);
+
+        for &filter_bogons in [false, true].iter() {
+            let config = ServerConfig {
+                filter_bogons,
+                ..hierarchy_config(&root)
+            };
+            let (response, _) =
+                resolve_with_stats(&question, ReqProtocol::UDP, &mut DNSCache::new(), &config)
+                    .await
+                    .unwrap();
+            let ips = crate::answer::Answer::from(&response).ips();
+            assert_eq!(
+                ips.contains(&Ipv4Addr::new(10, 0, 0, 1).into()),
+                !filter_bogons
+            );
+            assert!(ips.contains(&Ipv4Addr::new(93, 184, 216, 34).into()));
+        }
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    async fn batch_results_keep_the_order_of_their_names() {
+        let root = mock_servers(1).await.remove(0);
+        let names = ["one.example", "two.example", "three.example"];
+        let addr = |i: usize| Ipv4Addr::new(192, 0, 2, i as u8 + 1);
+        for (i, &name) in names.iter().enumerate() {
+            root.answer(name, QueryType::A, a_answer(name, addr(i)));
+        }
+        let config = ServerConfig {
+            batch_concurrency: 2,
+            ..hierarchy_config(&root)
+        };
+        let batch: Vec<_> = names
+            .iter()
+            .map(|&name| (name.to_owned(), QueryType::A))
+            .collect();
+        let results = resolve_batch(&batch, ReqProtocol::UDP, &DNSCache::new(), &config).await;
+        assert_eq!(results.len(), names.len());
+        for (i, result) in results.into_iter().enumerate() {
+            let expected = DNSRecord::new_a(names[i], addr(i), 300);
+            assert_eq!(result.unwrap().answers, vec![expected]);
+        }
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    async fn forwarded_zones_go_to_their_own_upstream() {
+        let servers = mock_servers(2).await;
+        let (root, internal) = (&servers[0], &servers[1]);
+        internal.answer(
+            "db.corp.example.com",
+            QueryType::A,
+            a_answer("db.corp.example.com", Ipv4Addr::new(10, 0, 0, 5)),
+        );
+        root.answer(
+            "www.example.com",
+            QueryType::A,
+            a_answer("www.example.com", Ipv4Addr::new(192, 0, 2, 80)),
+        );
+        let config = ServerConfig {
+            forward_zones: vec![("corp.example.com".to_owned(), internal.addr())],
+            ..hierarchy_config(root)
+        };
+        let mut cache = DNSCache::new();
+        for (qname, addr, server) in [
+            ("db.corp.example.com", Ipv4Addr::new(10, 0, 0, 5), internal),
+            ("www.example.com", Ipv4Addr::new(192, 0, 2, 80), root),
+        ]
+        .iter()
+        {
+            let question = DNSQuestion::new(qname.to_string(), QueryType::A);
+            let (response, stats) =
+                resolve_with_stats(&question, ReqProtocol::UDP, &mut cache, &config)
+                    .await
+                    .unwrap();
+            assert_eq!(response.get_random_a(), Some(*addr));
+            assert_eq!(stats.nameservers, vec![server.addr()]);
+        }
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    async fn stats_count_each_delegation_step() {
+        let servers = three_level_hierarchy().await;
+        let config = hierarchy_config(&servers[0]);
+        let mut cache = DNSCache::new();
+        let question = DNSQuestion::new("www.example.com".to_owned(), QueryType::A);
+        let (_, stats) = resolve_with_stats(&question, ReqProtocol::UDP, &mut cache, &config)
+            .await
+            .unwrap();
+        assert_eq!(stats.queries, 3);
+        assert_eq!(stats.nameservers.len(), 3);
+        assert_eq!(stats.max_depth, 0);
+        // Answered from the cache the second time round
+        let (_, stats) = resolve_with_stats(&question, ReqProtocol::UDP, &mut cache, &config)
+            .await
+            .unwrap();
+        assert_eq!(stats.queries, 0);
+    }
+
+    #[test]
+    fn source_ports_stay_in_their_inclusive_range() {
+        assert_eq!(random_source_port(65535, 65535), 65535);
+        assert_eq!(random_source_port(53000, 53000), 53000);
+        for _ in 0..100 {
+            let port = random_source_port(65534, 65535);
+            assert!(port == 65534 || port == 65535);
+        }
+    }
+}
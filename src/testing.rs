@@ -0,0 +1,168 @@
+use crate::buffer::{ArrayBuffer, VecBuffer};
+use crate::{is_subdomain_of, normalize_name, DNSPacket, QueryType, RCode, MIN_UDP_PAYLOAD};
+use eyre::Result;
+use std::collections::HashMap;
+use std::net::{self, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+#[derive(Debug, Default)]
+struct Responses {
+    // Exact (name, type) answers
+    answers: HashMap<(String, QueryType), DNSPacket>,
+    // Responses for any question under a zone, typically referrals
+    zones: Vec<(String, DNSPacket)>,
+}
+
+impl Responses {
+    fn respond(&self, request: &DNSPacket) -> DNSPacket {
+        let question = &request.questions[0];
+        let programmed = self
+            .answers
+            .get(&(normalize_name(&question.name), question.q_type))
+            .or_else(|| {
+                self.zones
+                    .iter()
+                    .filter(|(zone, _)| is_subdomain_of(&question.name, zone))
+                    .max_by_key(|(zone, _)| zone.len())
+                    .map(|(_, packet)| packet)
+            });
+        let mut response = match programmed {
+            Some(packet) => packet.clone(),
+            None => {
+                let mut packet = DNSPacket::new();
+                packet.header.res_code = RCode::REFUSED;
+                packet
+            }
+        };
+        response.header.id = request.header.id;
+        response.header.query_response = true;
+        response.set_question(question.clone());
+        response
+    }
+}
+
+// Nameserver answering UDP and TCP queries from programmed responses, for
+// driving the resolver through a fake delegation hierarchy in tests. Several
+// mocks can share a port on different loopback addresses (127.0.0.2, ...) so
+// glue records can point at them with a single `nameserver_port`.
+// UDP responses too large for the client are truncated like a real server would.
+#[derive(Debug, Clone)]
+pub struct MockNameServer {
+    addr: SocketAddr,
+    responses: Arc<Mutex<Responses>>,
+    tcp_queries: Arc<AtomicUsize>,
+}
+
+impl MockNameServer {
+    // Port 0 picks a free port, the same one is then used for TCP
+    pub async fn bind(addr: SocketAddr) -> Result<MockNameServer> {
+        let udp_socket = UdpSocket::bind(addr).await?;
+        let addr = udp_socket.local_addr()?;
+        let listener = TcpListener::from_std(net::TcpListener::bind(addr)?)?;
+        let server = MockNameServer {
+            addr,
+            responses: Arc::new(Mutex::new(Responses::default())),
+            tcp_queries: Arc::new(AtomicUsize::new(0)),
+        };
+        tokio::spawn(MockNameServer::serve_udp(
+            udp_socket,
+            server.responses.clone(),
+        ));
+        tokio::spawn(MockNameServer::serve_tcp(
+            listener,
+            server.responses.clone(),
+            server.tcp_queries.clone(),
+        ));
+        Ok(server)
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    // TCP connections accepted so far, the resolver sends one query per connection
+    pub fn tcp_queries(&self) -> usize {
+        self.tcp_queries.load(Ordering::SeqCst)
+    }
+
+    pub fn answer(&self, qname: &str, q_type: QueryType, packet: DNSPacket) {
+        self.responses
+            .lock()
+            .unwrap()
+            .answers
+            .insert((normalize_name(qname), q_type), packet);
+    }
+
+    // Answers every question under `zone` without a more specific programmed
+    // answer with `packet`, e.g. a referral to the zone's nameservers
+    pub fn answer_zone(&self, zone: &str, packet: DNSPacket) {
+        self.responses
+            .lock()
+            .unwrap()
+            .zones
+            .push((normalize_name(zone), packet));
+    }
+
+    async fn serve_udp(mut socket: UdpSocket, responses: Arc<Mutex<Responses>>) {
+        loop {
+            let mut req_buffer = ArrayBuffer::new();
+            let src = match req_buffer.recv_from(&mut socket).await {
+                Ok((_, src)) => src,
+                Err(_) => return,
+            };
+            let request = match DNSPacket::from_buffer(&mut req_buffer) {
+                Ok(request) if !request.questions.is_empty() => request,
+                _ => continue,
+            };
+            let mut response = responses.lock().unwrap().respond(&request);
+            let mut res_bytes = match response.to_bytes() {
+                Ok(res_bytes) => res_bytes,
+                Err(_) => continue,
+            };
+            let limit = request
+                .udp_payload_size()
+                .unwrap_or(MIN_UDP_PAYLOAD)
+                .max(MIN_UDP_PAYLOAD) as usize;
+            if res_bytes.len() > limit {
+                response.truncate();
+                res_bytes = match response.to_bytes() {
+                    Ok(res_bytes) => res_bytes,
+                    Err(_) => continue,
+                };
+            }
+            let _ = socket.send_to(&res_bytes, src).await;
+        }
+    }
+
+    async fn serve_tcp(
+        mut listener: TcpListener,
+        responses: Arc<Mutex<Responses>>,
+        tcp_queries: Arc<AtomicUsize>,
+    ) {
+        loop {
+            let (mut socket, _) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(_) => return,
+            };
+            let responses = responses.clone();
+            tcp_queries.fetch_add(1, Ordering::SeqCst);
+            tokio::spawn(async move {
+                let _ = MockNameServer::handle_tcp(&mut socket, responses).await;
+            });
+        }
+    }
+
+    async fn handle_tcp(socket: &mut TcpStream, responses: Arc<Mutex<Responses>>) -> Result<()> {
+        let mut req_buffer = VecBuffer::from_socket(socket).await?;
+        let request = DNSPacket::from_buffer(&mut req_buffer)?;
+        if request.questions.is_empty() {
+            return Ok(());
+        }
+        let mut response = responses.lock().unwrap().respond(&request);
+        let mut res_buffer = VecBuffer::new();
+        response.write(&mut res_buffer)?;
+        res_buffer.to_socket(socket).await
+    }
+}
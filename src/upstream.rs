@@ -0,0 +1,155 @@
+use rand::seq::SliceRandom;
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Recent round trips kept per upstream for the percentile summaries
+const MAX_SAMPLES: usize = 64;
+// What a failed query counts as, so an unreachable server sinks to the back
+const FAILURE_PENALTY: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencySummary {
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+}
+
+#[derive(Debug, Clone, Default)]
+struct UpstreamEntry {
+    srtt: Duration,
+    samples: VecDeque<Duration>,
+}
+
+impl UpstreamEntry {
+    fn record(&mut self, rtt: Duration) {
+        // Smoothed like BIND: 7/10 of the old value, 3/10 of the new sample
+        self.srtt = if self.samples.is_empty() {
+            rtt
+        } else {
+            (self.srtt * 7 + rtt * 3) / 10
+        };
+        if self.samples.len() == MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(rtt);
+    }
+}
+
+fn percentile(sorted: &[Duration], percent: usize) -> Duration {
+    sorted[(sorted.len() - 1) * percent / 100]
+}
+
+// Round trip times of every nameserver queried while recursing, shared between
+// all requests so each resolution benefits from what earlier ones measured
+#[derive(Debug, Clone, Default)]
+pub struct UpstreamStats {
+    map: Arc<Mutex<HashMap<IpAddr, UpstreamEntry>>>,
+}
+
+impl UpstreamStats {
+    pub fn new() -> UpstreamStats {
+        UpstreamStats::default()
+    }
+
+    pub fn record(&self, addr: IpAddr, rtt: Duration) {
+        self.map
+            .lock()
+            .unwrap()
+            .entry(addr)
+            .or_default()
+            .record(rtt);
+    }
+
+    pub fn record_failure(&self, addr: IpAddr) {
+        self.record(addr, FAILURE_PENALTY);
+    }
+
+    pub fn srtt(&self, addr: IpAddr) -> Option<Duration> {
+        self.map.lock().unwrap().get(&addr).map(|entry| entry.srtt)
+    }
+
+    pub fn summary(&self, addr: IpAddr) -> Option<LatencySummary> {
+        let map = self.map.lock().unwrap();
+        let entry = map.get(&addr)?;
+        let mut sorted = entry.samples.iter().copied().collect::<Vec<Duration>>();
+        if sorted.is_empty() {
+            return None;
+        }
+        sorted.sort();
+        Some(LatencySummary {
+            p50: percentile(&sorted, 50),
+            p90: percentile(&sorted, 90),
+            p99: percentile(&sorted, 99),
+        })
+    }
+
+    // Picks the nameserver with the lowest SRTT. Servers without any measurement
+    // are tried first so every server of a delegation gets a sample, randomly
+    // among themselves if `randomize` is set.
+    pub fn choose(&self, candidates: &[IpAddr], randomize: bool) -> Option<IpAddr> {
+        let map = self.map.lock().unwrap();
+        let unmeasured = candidates
+            .iter()
+            .filter(|addr| !map.contains_key(addr))
+            .copied()
+            .collect::<Vec<IpAddr>>();
+        if !unmeasured.is_empty() {
+            return if randomize {
+                unmeasured.choose(&mut rand::thread_rng()).copied()
+            } else {
+                unmeasured.first().copied()
+            };
+        }
+        candidates.iter().min_by_key(|addr| map[addr].srtt).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::net::Ipv4Addr;
+
+    fn glue() -> Vec<IpAddr> {
+        (1..=3)
+            .map(|i| IpAddr::V4(Ipv4Addr::new(192, 0, 2, i)))
+            .collect()
+    }
+
+    #[test]
+    fn randomized_choice_spreads_over_unmeasured_glue() {
+        let stats = UpstreamStats::new();
+        let picked = (0..200)
+            .filter_map(|_| stats.choose(&glue(), true))
+            .collect::<HashSet<IpAddr>>();
+        // Missing one of three in 200 fair draws has a chance of about 1e-35
+        assert_eq!(picked.len(), 3);
+        assert_eq!(stats.choose(&glue(), false), Some(glue()[0]));
+        assert_eq!(stats.choose(&[], true), None);
+    }
+
+    #[test]
+    fn slow_nameserver_loses_to_a_faster_one() {
+        let stats = UpstreamStats::new();
+        let (slow, fast, failed) = (glue()[0], glue()[1], glue()[2]);
+        stats.record(slow, Duration::from_millis(400));
+        stats.record(fast, Duration::from_millis(20));
+        stats.record_failure(failed);
+        for _ in 0..10 {
+            assert_eq!(stats.choose(&glue(), true), Some(fast));
+        }
+        // One slow sample only nudges a good SRTT, lots of them overtake it
+        stats.record(fast, Duration::from_millis(1000));
+        assert_eq!(stats.choose(&glue(), false), Some(fast));
+        for _ in 0..10 {
+            stats.record(fast, Duration::from_millis(1000));
+        }
+        assert_eq!(stats.choose(&glue(), false), Some(slow));
+
+        let summary = stats.summary(fast).unwrap();
+        assert_eq!(summary.p50, Duration::from_millis(1000));
+        assert_eq!(stats.summary(slow).unwrap().p99, Duration::from_millis(400));
+    }
+}
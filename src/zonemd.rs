@@ -0,0 +1,262 @@
+use crate::buffer::UncompressedBuffer;
+use crate::nsec3::canonical_wire_name;
+use crate::{is_subdomain_of, normalize_name, DNSRecord, QueryType};
+use eyre::{eyre, Result};
+use sha2::{Digest, Sha384, Sha512};
+use std::cmp::Ordering;
+
+// RFC 8976 section 5.2 and 5.3
+pub const ZONEMD_SCHEME_SIMPLE: u8 = 1;
+pub const ZONEMD_SHA384: u8 = 1;
+pub const ZONEMD_SHA512: u8 = 2;
+
+// Canonical order of names (RFC 4034 section 6.1): label by label from the
+// root down, lowercased, with a name sorting before every name below it
+fn canonical_name_cmp(a: &str, b: &str) -> Ordering {
+    let (a, b) = (normalize_name(a), normalize_name(b));
+    let a_labels = a.split('.').filter(|label| !label.is_empty()).rev();
+    let b_labels = b.split('.').filter(|label| !label.is_empty()).rev();
+    a_labels.cmp(b_labels)
+}
+
+// The record in canonical wire format: owner and embedded names lowercased and
+// uncompressed. Records of unknown types keep their opaque RDATA (RFC 3597
+// section 7), which is already canonical.
+fn canonical_record(record: &DNSRecord) -> Result<Vec<u8>> {
+    let mut record = record.clone();
+    match &mut record {
+        DNSRecord::NS { host, .. }
+        | DNSRecord::CNAME { host, .. }
+        | DNSRecord::PTR { host, .. }
+        | DNSRecord::MX { host, .. } => *host = host.to_lowercase(),
+        DNSRecord::SOA { mname, rname, .. } => {
+            *mname = mname.to_lowercase();
+            *rname = rname.to_lowercase();
+        }
+        _ => {}
+    }
+    let mut buf = UncompressedBuffer::default();
+    record.write(&mut buf)?;
+    let owner = canonical_wire_name(record.get_name());
+    buf.inner.buf[..owner.len()].copy_from_slice(&owner);
+    Ok(buf.inner.buf)
+}
+
+// Digest of the zone's records in canonical order, without duplicates and
+// without the apex ZONEMD RRset itself (RFC 8976 section 3.3.1)
+pub fn zonemd_digest(records: &[DNSRecord], zone: &str, hash_algorithm: u8) -> Result<Vec<u8>> {
+    let zone = normalize_name(zone);
+    let mut canonical = Vec::with_capacity(records.len());
+    for record in records {
+        if !is_subdomain_of(record.get_name(), &zone) {
+            continue;
+        }
+        if record.get_type() == QueryType::ZONEMD && normalize_name(record.get_name()) == zone {
+            continue;
+        }
+        canonical.push((record.get_name(), canonical_record(record)?));
+    }
+    // Past the owner name come type and class, then TTL and RDLENGTH, then RDATA
+    let owner_len = |name: &str| canonical_wire_name(name).len();
+    canonical.sort_by(|(a_name, a), (b_name, b)| {
+        let (a_owner, b_owner) = (owner_len(a_name), owner_len(b_name));
+        canonical_name_cmp(a_name, b_name)
+            .then_with(|| a[a_owner..a_owner + 4].cmp(&b[b_owner..b_owner + 4]))
+            .then_with(|| a[a_owner + 10..].cmp(&b[b_owner + 10..]))
+    });
+    // Duplicate RRs may come with different TTLs, only the first one is kept
+    canonical.dedup_by(|(a_name, a), (b_name, b)| {
+        let (a_owner, b_owner) = (owner_len(a_name), owner_len(b_name));
+        a[..a_owner + 4] == b[..b_owner + 4] && a[a_owner + 10..] == b[b_owner + 10..]
+    });
+
+    let data = canonical.into_iter().flat_map(|(_, record)| record);
+    match hash_algorithm {
+        ZONEMD_SHA384 => Ok(Sha384::new()
+            .chain_update(data.collect::<Vec<u8>>())
+            .finalize()
+            .to_vec()),
+        ZONEMD_SHA512 => Ok(Sha512::new()
+            .chain_update(data.collect::<Vec<u8>>())
+            .finalize()
+            .to_vec()),
+        _ => Err(eyre!(
+            "Unsupported ZONEMD hash algorithm {}",
+            hash_algorithm
+        )),
+    }
+}
+
+// Checks the zone against its apex ZONEMD records (RFC 8976 section 4). It
+// verifies if any supported one matches the SOA serial and the computed digest.
+pub fn verify_zonemd(records: &[DNSRecord], zone: &str) -> Result<bool> {
+    let apex = normalize_name(zone);
+    let soa_serial = records
+        .iter()
+        .find_map(|record| match record {
+            DNSRecord::SOA { name, serial, .. } if normalize_name(name) == apex => Some(*serial),
+            _ => None,
+        })
+        .ok_or_else(|| eyre!("Zone {:?} has no SOA at its apex", zone))?;
+    let mut supported = false;
+    for record in records {
+        let (serial, scheme, hash_algorithm, digest) = match record {
+            DNSRecord::ZONEMD {
+                name,
+                serial,
+                scheme,
+                hash_algorithm,
+                digest,
+                ..
+            } if normalize_name(name) == apex => (*serial, *scheme, *hash_algorithm, digest),
+            _ => continue,
+        };
+        if scheme != ZONEMD_SCHEME_SIMPLE
+            || (hash_algorithm != ZONEMD_SHA384 && hash_algorithm != ZONEMD_SHA512)
+        {
+            continue;
+        }
+        supported = true;
+        if serial == soa_serial && zonemd_digest(records, zone, hash_algorithm)? == *digest {
+            return Ok(true);
+        }
+    }
+    if !supported {
+        return Err(eyre!("Zone {:?} has no usable ZONEMD record", zone));
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CLASS_IN;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    fn ns(host: &str) -> DNSRecord {
+        DNSRecord::NS {
+            name: "example".to_owned(),
+            q_type: QueryType::NS,
+            class: CLASS_IN,
+            ttl: 86400,
+            len: 0,
+            host: host.to_owned(),
+        }
+    }
+
+    // RFC 8976 Appendix A.1, the simplest possible zone
+    fn simple_zone() -> Vec<DNSRecord> {
+        vec![
+            DNSRecord::SOA {
+                name: "example".to_owned(),
+                q_type: QueryType::SOA,
+                class: CLASS_IN,
+                ttl: 86400,
+                len: 0,
+                mname: "ns1.example".to_owned(),
+                rname: "admin.example".to_owned(),
+                serial: 2018031900,
+                refresh: 1800,
+                retry: 900,
+                expire: 604800,
+                minimum: 86400,
+            },
+            ns("ns1.example"),
+            ns("ns2.example"),
+            DNSRecord::new_a("ns1.example", Ipv4Addr::new(203, 0, 113, 63), 3600),
+            DNSRecord::new_aaaa(
+                "ns2.example",
+                "2001:db8::63".parse::<Ipv6Addr>().unwrap(),
+                3600,
+            ),
+        ]
+    }
+
+    fn zonemd(digest: Vec<u8>) -> DNSRecord {
+        DNSRecord::ZONEMD {
+            name: "example".to_owned(),
+            q_type: QueryType::ZONEMD,
+            class: CLASS_IN,
+            ttl: 86400,
+            len: 0,
+            serial: 2018031900,
+            scheme: ZONEMD_SCHEME_SIMPLE,
+            hash_algorithm: ZONEMD_SHA384,
+            digest,
+        }
+    }
+
+    fn hex(digest: &str) -> Vec<u8> {
+        (0..digest.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&digest[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn verifies_the_rfc_8976_simple_example_zone() {
+        let expected = hex(concat!(
+            "c68090d90a7aed716bc459f9340e3d7c1370d4d24b7e2fc3",
+            "a1ddc0b9a87153b9a9713b3c9ae5cc27777f98b8e730044c"
+        ));
+        let mut zone = simple_zone();
+        assert_eq!(
+            zonemd_digest(&zone, "example", ZONEMD_SHA384).unwrap(),
+            expected
+        );
+        zone.push(zonemd(expected));
+        assert!(verify_zonemd(&zone, "example.").unwrap());
+    }
+
+    #[test]
+    fn digest_ignores_order_case_and_duplicates() {
+        let zone = simple_zone();
+        let mut shuffled = zone.clone();
+        shuffled.reverse();
+        shuffled.push(ns("NS1.Example"));
+        assert_eq!(
+            zonemd_digest(&zone, "example", ZONEMD_SHA384).unwrap(),
+            zonemd_digest(&shuffled, "example", ZONEMD_SHA384).unwrap()
+        );
+    }
+
+    #[test]
+    fn duplicates_with_different_ttls_are_digested_once() {
+        let mut zone = simple_zone();
+        let mut duplicate = ns("ns1.example");
+        duplicate.set_ttl(300);
+        zone.push(duplicate);
+        assert_eq!(
+            zonemd_digest(&zone, "example", ZONEMD_SHA384).unwrap(),
+            zonemd_digest(&simple_zone(), "example", ZONEMD_SHA384).unwrap()
+        );
+    }
+
+    #[test]
+    fn unknown_records_are_part_of_the_digest() {
+        let unknown = |data: Vec<u8>| DNSRecord::UNKNOWN {
+            name: "example".to_owned(),
+            q_type: QueryType::UNKNOWN(65280),
+            class: CLASS_IN,
+            ttl: 3600,
+            len: data.len() as u16,
+            data,
+        };
+        let canonical = canonical_record(&unknown(vec![0xde, 0xad])).unwrap();
+        assert_eq!(&canonical[canonical.len() - 4..], &[0, 2, 0xde, 0xad]);
+
+        let mut zone = simple_zone();
+        zone.push(unknown(vec![0xde, 0xad]));
+        let digest = zonemd_digest(&zone, "example", ZONEMD_SHA384).unwrap();
+        assert_ne!(
+            digest,
+            zonemd_digest(&simple_zone(), "example", ZONEMD_SHA384).unwrap()
+        );
+        zone.pop();
+        zone.push(unknown(vec![0xbe, 0xef]));
+        assert_ne!(
+            digest,
+            zonemd_digest(&zone, "example", ZONEMD_SHA384).unwrap()
+        );
+    }
+}